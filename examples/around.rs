@@ -0,0 +1,27 @@
+//! Example of a logger linked as a single around middleware
+extern crate iron;
+extern crate logger;
+extern crate env_logger;
+
+use iron::prelude::*;
+use logger::LoggerBuilder;
+
+// A logger linked around the handler times everything it wraps,
+// so there is no before and after half to keep in order.
+fn main() {
+    env_logger::init().unwrap();
+
+    let mut chain = Chain::new(no_op_handler);
+
+    chain.link_around(LoggerBuilder::new().build_around());
+
+    println!("Run `RUST_LOG=logger=info cargo run --example around` to see logs.");
+    match Iron::new(chain).http("127.0.0.1:3000") {
+        Result::Ok(listening) => println!("{:?}", listening),
+        Result::Err(err) => panic!("{:?}", err),
+    }
+}
+
+fn no_op_handler(_: &mut Request) -> IronResult<Response> {
+    Ok(Response::with(iron::status::Ok))
+}
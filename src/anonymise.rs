@@ -0,0 +1,179 @@
+//! Anonymisation of IP addresses.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use client_ip::{normalise, parse_node};
+use hash::siphash;
+use redact::REDACTED;
+
+/// How the `Logger` anonymises IP addresses before they are logged.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IpAnonymisation {
+    /// Zero the last octet of IPv4 addresses and the last 80 bits of IPv6 addresses, so that
+    /// `192.0.2.123` is logged as `192.0.2.0` and `2001:db8:1:2:3:4:5:6` as `2001:db8:1::`.
+    /// This is the default.
+    Truncate,
+    /// Replace addresses with a hash keyed by the given secret, so that requests from the same
+    /// address can be correlated without the address being identifiable. The key should be
+    /// random, kept secret and changed periodically. Hashed addresses are logged without a port.
+    Hash([u8; 16]),
+}
+
+impl Default for IpAnonymisation {
+    fn default() -> IpAnonymisation {
+        IpAnonymisation::Truncate
+    }
+}
+
+// The key must stay secret for hashed addresses to stay anonymous, so it is
+// left out here.
+impl fmt::Debug for IpAnonymisation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpAnonymisation::Truncate => f.write_str("Truncate"),
+            IpAnonymisation::Hash(_) => f.write_str("Hash(..)"),
+        }
+    }
+}
+
+impl IpAnonymisation {
+    /// Render `ip` anonymised, with `port` if it is given and the address is truncated rather
+    /// than hashed.
+    pub fn render(&self, ip: IpAddr, port: Option<u16>) -> String {
+        match (*self, normalise(ip)) {
+            (IpAnonymisation::Truncate, ip) => {
+                match port {
+                    Some(port) => format!("{}", SocketAddr::new(truncate(ip), port)),
+                    None => format!("{}", truncate(ip)),
+                }
+            },
+            (IpAnonymisation::Hash(ref key), IpAddr::V4(ip)) => format!("{:016x}", siphash(key, &ip.octets())),
+            (IpAnonymisation::Hash(ref key), IpAddr::V6(ip)) => format!("{:016x}", siphash(key, &ip.octets())),
+        }
+    }
+}
+
+/// Anonymise the client addresses in a value of the header `name`, if it is one
+/// of the forwarding headers `Forwarded`, `X-Forwarded-For` or `X-Real-IP`.
+/// Other headers are left alone and give `None`.
+pub fn forwarding_header(mode: IpAnonymisation, name: &str, value: &str) -> Option<String> {
+    if name.eq_ignore_ascii_case("x-forwarded-for") || name.eq_ignore_ascii_case("x-real-ip") {
+        return Some(value.split(',')
+                         .map(|node| anonymise_node(mode, node.trim()))
+                         .collect::<Vec<String>>()
+                         .join(", "));
+    }
+    if !name.eq_ignore_ascii_case("forwarded") {
+        return None;
+    }
+
+    // Rewrite the `for` and `by` nodes of each element, keeping other
+    // parameters such as `proto` as they are.
+    Some(value.split(',')
+              .map(|element| {
+                  element.split(';')
+                      .map(|pair| match pair.find('=') {
+                          Some(eq) if is_node_parameter(&pair[..eq]) => {
+                              let node = pair[eq + 1..].trim().trim_matches('"');
+                              format!("{}=\"{}\"", &pair[..eq], anonymise_node(mode, node))
+                          },
+                          _ => pair.to_owned(),
+                      })
+                      .collect::<Vec<String>>()
+                      .join(";")
+              })
+              .collect::<Vec<String>>()
+              .join(","))
+}
+
+fn is_node_parameter(name: &str) -> bool {
+    let name = name.trim();
+    name.eq_ignore_ascii_case("for") || name.eq_ignore_ascii_case("by")
+}
+
+// Anonymise a forwarded node. The `unknown` and obfuscated `_name` nodes of
+// RFC 7239 reveal nothing, but anything else that is not an address might.
+fn anonymise_node(mode: IpAnonymisation, node: &str) -> String {
+    match parse_node(node) {
+        Some(ip) => mode.render(ip, None),
+        None if node == "unknown" || node.starts_with('_') => node.to_owned(),
+        None => REDACTED.to_owned(),
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], 0))
+        },
+        IpAddr::V6(ip) => {
+            // Keep the first 48 bits, which identify the site rather than the host.
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], 0, 0, 0, 0, 0))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{forwarding_header, IpAnonymisation};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn truncates_the_last_octet_of_ipv4() {
+        assert_eq!(IpAnonymisation::Truncate.render(ip("192.0.2.123"), None), "192.0.2.0");
+        assert_eq!(IpAnonymisation::Truncate.render(ip("192.0.2.0"), None), "192.0.2.0");
+    }
+
+    #[test]
+    fn keeps_the_first_48_bits_of_ipv6() {
+        assert_eq!(IpAnonymisation::Truncate.render(ip("2001:db8:1:2:3:4:5:6"), None), "2001:db8:1::");
+        assert_eq!(IpAnonymisation::Truncate.render(ip("2001:db8:1:ffff::1"), None), "2001:db8:1::");
+        assert_eq!(IpAnonymisation::Truncate.render(ip("::1"), None), "::");
+    }
+
+    #[test]
+    fn truncates_ipv4_mapped_addresses_as_ipv4() {
+        assert_eq!(IpAnonymisation::Truncate.render(ip("::ffff:192.0.2.123"), None), "192.0.2.0");
+
+        let key = [3; 16];
+        assert_eq!(IpAnonymisation::Hash(key).render(ip("::ffff:192.0.2.123"), None),
+                   IpAnonymisation::Hash(key).render(ip("192.0.2.123"), None));
+    }
+
+    #[test]
+    fn keeps_the_port_when_truncating() {
+        assert_eq!(IpAnonymisation::Truncate.render(ip("192.0.2.123"), Some(8080)), "192.0.2.0:8080");
+        assert_eq!(IpAnonymisation::Truncate.render(ip("2001:db8:1:2::6"), Some(443)), "[2001:db8:1::]:443");
+    }
+
+    #[test]
+    fn drops_the_port_when_hashing() {
+        let hash = IpAnonymisation::Hash([3; 16]);
+        let hashed = hash.render(ip("192.0.2.123"), None);
+        assert_eq!(hashed.len(), 16);
+        assert_eq!(hash.render(ip("192.0.2.123"), Some(8080)), hashed);
+        assert!(hash.render(ip("192.0.2.124"), None) != hashed);
+        assert!(IpAnonymisation::Hash([4; 16]).render(ip("192.0.2.123"), None) != hashed);
+        assert_eq!(format!("{:?}", hash), "Hash(..)");
+    }
+
+    #[test]
+    fn anonymises_forwarding_headers() {
+        let mode = IpAnonymisation::Truncate;
+        assert_eq!(forwarding_header(mode, "X-Forwarded-For", "203.0.113.7, 10.0.0.2:80, unknown, evil"),
+                   Some("203.0.113.0, 10.0.0.0, unknown, [REDACTED]".to_owned()));
+        assert_eq!(forwarding_header(mode, "x-real-ip", "203.0.113.7"), Some("203.0.113.0".to_owned()));
+        assert_eq!(forwarding_header(mode, "Forwarded",
+                                     r#"for="[2001:db8:cafe::17]:4711";proto=https, For=_hidden;by=10.0.0.1"#),
+                   Some(r#"for="2001:db8:cafe::";proto=https, For="_hidden";by="10.0.0.0""#.to_owned()));
+        assert_eq!(forwarding_header(mode, "User-Agent", "203.0.113.7"), None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Configuration of the logger middleware.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+
+use iron::{Request, Response};
+
+use log::LogLevel;
+
+use anonymise::IpAnonymisation;
+use client_ip::{Cidr, ForwardedHeader};
+use clock::{Clock, SystemClock};
+use filter::RequestFilter;
+use format::Format;
+use level::{StatusClass, StatusLevels};
+use output::OutputMode;
+use redact::{DEFAULT_MASKED_HEADERS, HeaderMask};
+use sampling::Sampler;
+use Logger;
+
+/// The configuration shared by the before and after halves of a `Logger`.
+pub struct Config {
+    pub format: Format,
+    pub log_after_body: bool,
+    pub output_mode: OutputMode,
+    pub levels: StatusLevels,
+    pub target: String,
+    pub include: Vec<RequestFilter>,
+    pub exclude: Vec<RequestFilter>,
+    pub predicate: Option<Box<Fn(&Request, &Response, Duration) -> bool + Send + Sync>>,
+    pub slow_threshold: Option<Duration>,
+    pub slow_thresholds: Vec<(String, Duration)>,
+    pub sampler: Sampler,
+    pub clock: Box<Clock>,
+    pub request_id_header: Option<String>,
+    pub trusted_proxies: Vec<Cidr>,
+    pub forwarded_header: ForwardedHeader,
+    pub ip_anonymisation: Option<IpAnonymisation>,
+    pub redacted_query_params: Vec<String>,
+    pub masked_headers: Vec<String>,
+    pub header_mask: HeaderMask,
+    pub missing_response_time: String,
+
+    // The number of requests seen without a start time.
+    pub missing_start_time: AtomicUsize,
+}
+
+/// A builder for configuring a pair of `Logger` middlewares.
+///
+/// ```ignore
+/// let (logger_before, logger_after) = LoggerBuilder::new()
+///     .format(Format::combined())
+///     .output_mode(OutputMode::Json)
+///     .build();
+/// ```
+pub struct LoggerBuilder {
+    config: Config,
+}
+
+impl LoggerBuilder {
+    /// Create a builder with the default configuration, which logs every request in the default
+    /// `Format` as a line of text, at info level for `1xx`, `2xx` and `3xx` responses, warn for
+    /// `4xx` responses and responses without a status, and error for `5xx` responses.
+    pub fn new() -> LoggerBuilder {
+        LoggerBuilder {
+            config: Config {
+                format: Format::default(),
+                log_after_body: false,
+                output_mode: OutputMode::Text,
+                levels: StatusLevels::new(),
+                target: "logger".to_owned(),
+                include: Vec::new(),
+                exclude: Vec::new(),
+                predicate: None,
+                slow_threshold: None,
+                slow_thresholds: Vec::new(),
+                sampler: Sampler::new(),
+                clock: Box::new(SystemClock),
+                request_id_header: None,
+                trusted_proxies: Vec::new(),
+                forwarded_header: ForwardedHeader::default(),
+                ip_anonymisation: None,
+                redacted_query_params: Vec::new(),
+                masked_headers: DEFAULT_MASKED_HEADERS.iter().map(|&name| name.to_owned()).collect(),
+                header_mask: HeaderMask::default(),
+                missing_response_time: "-".to_owned(),
+                missing_start_time: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    /// Use `format` for each log line.
+    pub fn format(mut self, format: Format) -> LoggerBuilder {
+        self.config.format = format;
+        self
+    }
+
+    /// Delay each log line until the response body has been written to the client, so that
+    /// `{bytes-sent}` reports the number of bytes actually sent rather than the `Content-Length`
+    /// header.
+    pub fn log_after_body(mut self, log_after_body: bool) -> LoggerBuilder {
+        self.config.log_after_body = log_after_body;
+        self
+    }
+
+    /// Choose how each log line is written out, for example as a JSON object with
+    /// `OutputMode::Json` or as logfmt pairs with `OutputMode::Logfmt`.
+    pub fn output_mode(mut self, output_mode: OutputMode) -> LoggerBuilder {
+        self.config.output_mode = output_mode;
+        self
+    }
+
+    /// Log responses with a status in `class` at `level`.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new().status_class_level(StatusClass::ClientError, LogLevel::Info)
+    /// ```
+    pub fn status_class_level(mut self, class: StatusClass, level: LogLevel) -> LoggerBuilder {
+        self.config.levels.set_class(class, level);
+        self
+    }
+
+    /// Log responses with the status `code` at `level`, overriding the level for its class.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new().status_level(404, LogLevel::Info)
+    /// ```
+    pub fn status_level(mut self, code: u16, level: LogLevel) -> LoggerBuilder {
+        self.config.levels.set_code(code, level);
+        self
+    }
+
+    /// Log responses without a status, or with a status outside the five classes, at `level`.
+    pub fn missing_status_level(mut self, level: LogLevel) -> LoggerBuilder {
+        self.config.levels.set_missing(level);
+        self
+    }
+
+    /// Log with the given `log` target instead of `logger`, so that the lines of different chains
+    /// can be filtered and routed independently by the installed logger:
+    ///
+    /// ```ignore
+    /// let (api_before, api_after) = LoggerBuilder::new().target("access::api").build();
+    /// let (static_before, static_after) = LoggerBuilder::new().target("access::static").build();
+    /// // RUST_LOG=access::api=info
+    /// ```
+    pub fn target<S: Into<String>>(mut self, target: S) -> LoggerBuilder {
+        self.config.target = target.into();
+        self
+    }
+
+    /// Do not log requests matching `filter`, for example a liveness probe:
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .exclude(RequestFilter::Path("/healthz".to_owned()))
+    ///     .exclude(RequestFilter::Method(Method::Options))
+    /// ```
+    pub fn exclude(mut self, filter: RequestFilter) -> LoggerBuilder {
+        self.config.exclude.push(filter);
+        self
+    }
+
+    /// Only log requests matching `filter`, or any other filter passed to `include`. Requests
+    /// matching a filter passed to `exclude` are still not logged.
+    pub fn include(mut self, filter: RequestFilter) -> LoggerBuilder {
+        self.config.include.push(filter);
+        self
+    }
+
+    /// Only log requests for which `predicate` returns `true`, given the request, the response and
+    /// the response time. It is called before the log line is rendered, so requests that are not
+    /// logged cost little more than the call itself. Requests excluded by the path and method
+    /// filters are not passed to it.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new().filter(|_req, res, duration| {
+    ///     res.status.map_or(true, |status| status.to_u16() >= 400) || duration > Duration::from_millis(500)
+    /// })
+    /// ```
+    ///
+    /// If the logger `BeforeMiddleware` did not run, the response time is zero.
+    pub fn filter<F>(mut self, predicate: F) -> LoggerBuilder
+        where F: Fn(&Request, &Response, Duration) -> bool + Send + Sync + 'static
+    {
+        self.config.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Treat requests taking longer than `threshold` as slow. Slow requests are always logged,
+    /// even if a filter would drop them, at warn level or above, and are marked with `slow=true`
+    /// (or a `slow` field in the structured output modes).
+    pub fn slow_threshold(mut self, threshold: Duration) -> LoggerBuilder {
+        self.config.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Treat requests whose path starts with `prefix` as slow when they take longer than
+    /// `threshold`, instead of the threshold given to `slow_threshold`. When several prefixes
+    /// match a path, the longest applies.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .slow_threshold(Duration::from_millis(500))
+    ///     .slow_threshold_for("/reports/", Duration::from_secs(5))
+    /// ```
+    pub fn slow_threshold_for<S: Into<String>>(mut self, prefix: S, threshold: Duration) -> LoggerBuilder {
+        self.config.slow_thresholds.push((prefix.into(), threshold));
+        self
+    }
+
+    /// Only log a fraction `rate` of requests, between `0.0` and `1.0`. Responses with a `5xx`
+    /// status are still always logged unless `sample_rate_for` says otherwise, and so are slow
+    /// requests. Sampled lines are marked with `sample_rate=0.1` (or a `sample_rate` field in the
+    /// structured output modes), so that counts can be re-weighted downstream.
+    pub fn sample_rate(mut self, rate: f64) -> LoggerBuilder {
+        self.config.sampler.set_rate(rate);
+        self
+    }
+
+    /// Log a fraction `rate` of the responses with a status in `class`, instead of the rate given
+    /// to `sample_rate`.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .sample_rate(0.01)
+    ///     .sample_rate_for(StatusClass::ClientError, 0.1)
+    /// ```
+    pub fn sample_rate_for(mut self, class: StatusClass, rate: f64) -> LoggerBuilder {
+        self.config.sampler.set_class_rate(class, rate);
+        self
+    }
+
+    /// Make the sampling decision deterministically from the value of the `header` request
+    /// header, such as a request or trace id, falling back to a random decision when it is
+    /// absent. The value is hashed with 64-bit FNV-1a and the request is kept when the hash
+    /// divided by 2^64 is less than the sample rate, so every service sampling on the same
+    /// header at the same rate keeps the same requests.
+    pub fn sample_by_header<S: Into<String>>(mut self, header: S) -> LoggerBuilder {
+        self.config.sampler.set_header(header.into());
+        self
+    }
+
+    /// Measure and timestamp requests with `clock` instead of the `SystemClock`, for example to
+    /// inject a fake time in tests.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> LoggerBuilder {
+        self.config.clock = Box::new(clock);
+        self
+    }
+
+    /// Give each request an id, taken from the `header` request header when the client or a proxy
+    /// sent one, or else newly generated. The id is stored in the request extensions under the
+    /// `RequestId` key for handlers to use, echoed in the `header` response header, and can be
+    /// logged with `{request-id}`.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .request_id("X-Request-Id")
+    ///     .format(Format::new("{request-id} {method} {uri} {status}").unwrap())
+    /// ```
+    pub fn request_id<S: Into<String>>(mut self, header: S) -> LoggerBuilder {
+        self.config.request_id_header = Some(header.into());
+        self
+    }
+
+    /// Trust the proxies with addresses in `proxies` to report the address of the client they
+    /// forward requests for, when resolving `{client-ip}`. Forwarding headers are only believed
+    /// from trusted proxies, so that clients cannot spoof their address.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .trusted_proxy("127.0.0.1".parse().unwrap())
+    ///     .trusted_proxy("10.0.0.0/8".parse().unwrap())
+    /// ```
+    pub fn trusted_proxy(mut self, proxies: Cidr) -> LoggerBuilder {
+        self.config.trusted_proxies.push(proxies);
+        self
+    }
+
+    /// Read the address of the client from `header` when a request comes from a trusted proxy.
+    /// The default is `X-Forwarded-For`. Only this header is believed, so it must be the one the
+    /// trusted proxies set, replacing or appending to whatever the client sent.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .trusted_proxy("10.0.0.0/8".parse().unwrap())
+    ///     .forwarded_header(ForwardedHeader::Forwarded)
+    /// ```
+    pub fn forwarded_header(mut self, header: ForwardedHeader) -> LoggerBuilder {
+        self.config.forwarded_header = header;
+        self
+    }
+
+    /// Anonymise every IP address the `Logger` writes, from `{ip-addr}`, `{remote-ip}` and
+    /// `{client-ip}`, as well as those in the `Forwarded`, `X-Forwarded-For` and `X-Real-IP`
+    /// headers when they are logged with `{req-header:...}`, as `mode` describes. Without this, only placeholders with the `anon`
+    /// modifier, like `{remote-ip:anon}`, are anonymised, by truncation.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .anonymise_ips(IpAnonymisation::Truncate)
+    /// ```
+    pub fn anonymise_ips(mut self, mode: IpAnonymisation) -> LoggerBuilder {
+        self.config.ip_anonymisation = Some(mode);
+        self
+    }
+
+    /// Replace the values of query parameters whose names match `pattern` with `[REDACTED]` in
+    /// `{uri}`, `{request-line}` and the logger's own warnings. Names are percent-decoded and
+    /// matched case-insensitively against the whole of `pattern`, in which `*` matches any run of
+    /// characters and `?` any single character:
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .redact_query_param("password")
+    ///     .redact_query_param("*token*")
+    /// // GET /login?user=bob&Password=hunter2 is logged as GET /login?user=bob&Password=[REDACTED]
+    /// ```
+    pub fn redact_query_param<S: Into<String>>(mut self, pattern: S) -> LoggerBuilder {
+        self.config.redacted_query_params.push(pattern.into().to_lowercase());
+        self
+    }
+
+    /// Mask the values of headers whose names match `pattern` wherever `{req-header:...}` and
+    /// `{res-header:...}` render them. `pattern` is a case-insensitive glob over the whole header
+    /// name, in which `?` stands for one character and `*` for any number of them, `-` included.
+    /// `Authorization`, `Proxy-Authorization`, `Cookie`, `Set-Cookie` and names matching
+    /// `*api-key`, `*-token` or `*-secret` are masked by default.
+    ///
+    /// ```ignore
+    /// LoggerBuilder::new()
+    ///     .mask_header("X-Session")
+    ///     .mask_header("X-Signature-*")
+    /// ```
+    pub fn mask_header<S: Into<String>>(mut self, pattern: S) -> LoggerBuilder {
+        self.config.masked_headers.push(pattern.into().to_lowercase());
+        self
+    }
+
+    /// Stop masking the values of headers matching `pattern`, which must be one of the default
+    /// patterns or a pattern passed to `mask_header`, in any letter case.
+    pub fn unmask_header<S: Into<String>>(mut self, pattern: S) -> LoggerBuilder {
+        let pattern = pattern.into().to_lowercase();
+        self.config.masked_headers.retain(|masked| *masked != pattern);
+        self
+    }
+
+    /// Mask sensitive header values as `mask` describes. The default replaces them with
+    /// `[REDACTED]`.
+    pub fn header_mask(mut self, mask: HeaderMask) -> LoggerBuilder {
+        self.config.header_mask = mask;
+        self
+    }
+
+    /// Render `{response-time}` as `text` when the request has no start time because the logger
+    /// `BeforeMiddleware` did not run first. The default is `-`.
+    pub fn missing_response_time<S: Into<String>>(mut self, text: S) -> LoggerBuilder {
+        self.config.missing_response_time = text.into();
+        self
+    }
+
+    /// Create the pair of `Logger` middlewares, to be linked as the first `BeforeMiddleware` and
+    /// the last `AfterMiddleware` of a chain.
+    pub fn build(self) -> (Logger, Logger) {
+        let config = Arc::new(self.config);
+        (Logger { config: config.clone() }, Logger { config: config })
+    }
+
+    /// Create a single `Logger` to be linked as `AroundMiddleware`, which times and logs the
+    /// whole handler it wraps, including any `IronError` it returns:
+    ///
+    /// ```ignore
+    /// let mut chain = Chain::new(handler);
+    /// chain.link_around(LoggerBuilder::new().build_around());
+    /// ```
+    pub fn build_around(self) -> Logger {
+        Logger { config: Arc::new(self.config) }
+    }
+}
+
+impl Default for LoggerBuilder {
+    fn default() -> LoggerBuilder {
+        LoggerBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redact::is_masked_header;
+
+    use super::LoggerBuilder;
+
+    #[test]
+    fn masks_and_unmasks_headers_in_any_case() {
+        let builder = LoggerBuilder::new()
+            .mask_header("X-Session")
+            .unmask_header("COOKIE")
+            .unmask_header("*-Token");
+        let masked = &builder.config.masked_headers;
+
+        assert!(is_masked_header("x-session", masked));
+        assert!(is_masked_header("X-SESSION", masked));
+        assert!(is_masked_header("Authorization", masked));
+        assert!(!is_masked_header("Cookie", masked));
+        assert!(!is_masked_header("X-Auth-Token", masked));
+    }
+}
@@ -0,0 +1,366 @@
+//! Resolving the address of the client behind trusted proxies.
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use iron::Request;
+
+use header_value;
+
+/// A block of IP addresses in CIDR notation, such as `10.0.0.0/8` or `fd00::/8`. A single address
+/// without a prefix length, such as `127.0.0.1`, is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in this block. IPv4-mapped IPv6 addresses, like `::ffff:10.0.0.1`, are
+    /// treated as the IPv4 addresses they map.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, normalise(*ip)) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) =>
+                prefix_matches(&block.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(block), IpAddr::V6(ip)) =>
+                prefix_matches(&block.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Cidr, ParseCidrError> {
+        let (addr, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = try!(addr.parse().map_err(|_| ParseCidrError(())));
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => try!(prefix.parse().map_err(|_| ParseCidrError(()))),
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(ParseCidrError(()));
+        }
+
+        // A block of IPv4-mapped addresses, like `::ffff:10.0.0.0/104`, is
+        // the block of IPv4 addresses it maps, as addresses are compared.
+        match (addr, normalise(addr)) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) if prefix >= 96 => Ok(Cidr { addr: IpAddr::V4(v4), prefix: prefix - 96 }),
+            _ => Ok(Cidr { addr: addr, prefix: prefix }),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// An error returned when parsing a `Cidr` fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseCidrError(());
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for ParseCidrError {
+    fn description(&self) -> &str {
+        "invalid CIDR address block"
+    }
+}
+
+fn prefix_matches(block: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let whole = (prefix / 8) as usize;
+    let rest = prefix % 8;
+
+    if block[..whole] != ip[..whole] {
+        return false;
+    }
+    if rest == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest);
+    block[whole] & mask == ip[whole] & mask
+}
+
+/// Treat IPv4-mapped IPv6 addresses as IPv4.
+pub fn normalise(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+            } else {
+                IpAddr::V6(v6)
+            }
+        },
+        ip => ip,
+    }
+}
+
+/// The header a trusted proxy uses to report the address of the client it
+/// forwards requests for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: client, proxy1, proxy2`, as set by nginx and most load balancers. This is
+    /// the default.
+    XForwardedFor,
+    /// `Forwarded: for=client, for=proxy1`, as standardised by RFC 7239.
+    Forwarded,
+    /// `X-Real-IP: client`, holding a single address.
+    XRealIp,
+}
+
+impl Default for ForwardedHeader {
+    fn default() -> ForwardedHeader {
+        ForwardedHeader::XForwardedFor
+    }
+}
+
+impl ForwardedHeader {
+    fn name(&self) -> &'static str {
+        match *self {
+            ForwardedHeader::XForwardedFor => "X-Forwarded-For",
+            ForwardedHeader::Forwarded => "Forwarded",
+            ForwardedHeader::XRealIp => "X-Real-IP",
+        }
+    }
+
+    // The addresses in a value of this header, from the farthest hop to the nearest.
+    fn hops(&self, value: &str) -> Vec<Option<IpAddr>> {
+        match *self {
+            ForwardedHeader::XForwardedFor => x_forwarded_for(value),
+            ForwardedHeader::Forwarded => forwarded_for(value),
+            ForwardedHeader::XRealIp => vec![parse_node(value.trim())],
+        }
+    }
+}
+
+/// The address of the client that made the request.
+///
+/// If the request came from a trusted proxy, the addresses it forwarded in
+/// `header` are walked from the nearest hop outwards until an address that is
+/// not a trusted proxy is found. Other forwarding headers are ignored, as a
+/// proxy passes on whatever the client sent in headers it does not manage, and
+/// so are the headers of untrusted peers, since anyone can send them.
+pub fn client_ip(req: &Request, header: ForwardedHeader, trusted: &[Cidr]) -> IpAddr {
+    let value = header_value(&req.headers, header.name());
+    resolve(req.remote_addr.ip(), header, value.as_ref().map(|value| &value[..]), trusted)
+}
+
+// Resolve the client address from the address of the peer and the value of
+// the forwarding header, if it was sent.
+fn resolve(peer: IpAddr, header: ForwardedHeader, value: Option<&str>, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+
+    let peer = normalise(peer);
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let hops = match value {
+        Some(value) => header.hops(value),
+        None => return peer,
+    };
+
+    // A header without any addresses, such as a `Forwarded` header with no
+    // `for=` elements, is as good as no header at all.
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(hop) => {
+                client = hop;
+                if !is_trusted(&hop) {
+                    break;
+                }
+            },
+            // An unknown or obfuscated hop; nothing beyond it can be
+            // traced, so the nearest known address is the best there is.
+            None => break,
+        }
+    }
+    client
+}
+
+// The `for` addresses of a `Forwarded` header, from the farthest hop to the nearest.
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value.split(',')
+        .filter_map(|element| {
+            element.split(';')
+                .filter_map(|pair| {
+                    let pair = pair.trim();
+                    match pair.find('=') {
+                        Some(eq) if pair[..eq].eq_ignore_ascii_case("for") => Some(&pair[eq + 1..]),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+        .map(|node| parse_node(node.trim_matches('"')))
+        .collect()
+}
+
+// The addresses of an `X-Forwarded-For` header, from the farthest hop to the nearest.
+fn x_forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value.split(',').map(|node| parse_node(node.trim())).collect()
+}
+
+/// Parse a forwarded node, which may be an address with or without a port,
+/// and with or without brackets around an IPv6 address.
+pub fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(normalise(ip));
+    }
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(normalise(addr.ip()));
+    }
+    if node.starts_with('[') && node.ends_with(']') {
+        return Ipv6Addr::from_str(&node[1..node.len() - 1]).ok().map(|ip| normalise(IpAddr::V6(ip)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{resolve, parse_node, Cidr, ForwardedHeader};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidrs(blocks: &[&str]) -> Vec<Cidr> {
+        blocks.iter().map(|block| block.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_prefix_lengths() {
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("203.0.113.7")));
+        assert!(!all.contains(&ip("2001:db8::1")));
+
+        let pair: Cidr = "192.0.2.4/31".parse().unwrap();
+        assert!(pair.contains(&ip("192.0.2.4")));
+        assert!(pair.contains(&ip("192.0.2.5")));
+        assert!(!pair.contains(&ip("192.0.2.6")));
+        assert!(!pair.contains(&ip("192.0.2.3")));
+
+        let single: Cidr = "192.0.2.4".parse().unwrap();
+        assert!(single.contains(&ip("192.0.2.4")));
+        assert!(!single.contains(&ip("192.0.2.5")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&ip("fd12:3456::1")));
+        assert!(!v6.contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/-1".parse::<Cidr>().is_err());
+        assert!("192.0.2/24".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_addresses_as_ipv4() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(&ip("::ffff:11.1.2.3")));
+
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+    }
+
+    #[test]
+    fn parses_forwarded_nodes() {
+        assert_eq!(parse_node("192.0.2.43"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node("192.0.2.43:47011"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node("[2001:db8:cafe::17]"), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("[2001:db8:cafe::17]:4711"), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        for &header in &[ForwardedHeader::XForwardedFor, ForwardedHeader::Forwarded, ForwardedHeader::XRealIp] {
+            assert_eq!(resolve(ip("203.0.113.7"), header, Some("for=6.6.6.6"), &trusted), ip("203.0.113.7"));
+            assert_eq!(resolve(ip("203.0.113.7"), header, Some("6.6.6.6"), &trusted), ip("203.0.113.7"));
+        }
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        // The client prepended a spoofed address; the trusted proxy appended
+        // the address it actually saw.
+        let value = Some("6.6.6.6, 203.0.113.7, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::XForwardedFor, value, &trusted), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn stops_at_an_unknown_hop() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let value = Some("203.0.113.7, unknown, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::XForwardedFor, value, &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn uses_the_farthest_hop_when_all_are_trusted() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let value = Some("10.0.0.3, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::XForwardedFor, value, &trusted), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn reads_the_forwarded_header() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let value = Some(r#"for="[2001:db8:cafe::17]:4711";proto=https, For=10.0.0.2;by=10.0.0.1"#);
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::Forwarded, value, &trusted), ip("2001:db8:cafe::17"));
+    }
+
+    #[test]
+    fn treats_a_forwarded_header_without_addresses_as_absent() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let value = Some("proto=https;by=10.0.0.1");
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::Forwarded, value, &trusted), ip("10.0.0.1"));
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::Forwarded, None, &trusted), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn reads_x_real_ip() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::XRealIp, Some(" 203.0.113.7 "), &trusted),
+                   ip("203.0.113.7"));
+        assert_eq!(resolve(ip("10.0.0.1"), ForwardedHeader::XRealIp, Some("nonsense"), &trusted), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn trusts_ipv4_mapped_peers() {
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        assert_eq!(resolve(ip("::ffff:10.0.0.1"), ForwardedHeader::XForwardedFor, Some("203.0.113.7"), &trusted),
+                   ip("203.0.113.7"));
+    }
+}
@@ -0,0 +1,31 @@
+//! Sources of time for measuring and timestamping requests.
+
+use time;
+
+/// A source of time for the `Logger`. Response times are measured with a monotonic clock, so that
+/// they are not disturbed by adjustments to the system clock, while `{request-time}` uses the
+/// wall clock.
+///
+/// The default is `SystemClock`; tests can substitute a fake clock with `LoggerBuilder::clock`.
+pub trait Clock: Send + Sync {
+    /// The current time of a monotonic clock in nanoseconds. Only the difference between two
+    /// readings is meaningful.
+    fn monotonic_ns(&self) -> u64;
+
+    /// The current wall-clock time in the local timezone.
+    fn now(&self) -> time::Tm;
+}
+
+/// The system's monotonic and wall clocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn monotonic_ns(&self) -> u64 {
+        time::precise_time_ns()
+    }
+
+    fn now(&self) -> time::Tm {
+        time::now()
+    }
+}
@@ -0,0 +1,66 @@
+//! A response body adapter that counts the bytes written to the client.
+
+use std::io::{self, Write};
+
+use iron::response::{ResponseBody, WriteBody};
+
+/// Wraps a `WriteBody`, counting the bytes it writes and reporting the total
+/// once the body has been written, or dropped without being written.
+pub struct CountingBody {
+    inner: Box<WriteBody + Send>,
+    written: u64,
+    on_finish: Option<Box<Fn(u64) + Send>>,
+}
+
+impl CountingBody {
+    /// Wrap `inner`, calling `on_finish` exactly once with the number of bytes written.
+    pub fn new(inner: Box<WriteBody + Send>, on_finish: Box<Fn(u64) + Send>) -> CountingBody {
+        CountingBody {
+            inner: inner,
+            written: 0,
+            on_finish: Some(on_finish),
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish(self.written);
+        }
+    }
+}
+
+impl WriteBody for CountingBody {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        let result = {
+            let mut counter = CountingWriter { inner: res, written: &mut self.written };
+            let mut body = ResponseBody::new(&mut counter);
+            self.inner.write_body(&mut body)
+        };
+
+        self.finish();
+        result
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+struct CountingWriter<'a, W: Write + 'a> {
+    inner: &'a mut W,
+    written: &'a mut u64,
+}
+
+impl<'a, W: Write> Write for CountingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        *self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
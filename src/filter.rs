@@ -0,0 +1,131 @@
+//! Rules for choosing which requests are logged.
+
+use iron::method::Method;
+
+/// A rule matching requests by their path or method, used with `LoggerBuilder::exclude` and
+/// `LoggerBuilder::include` to choose which requests are logged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RequestFilter {
+    /// Match requests whose path is exactly this, e.g. `/healthz`.
+    Path(String),
+    /// Match requests whose path starts with this, e.g. `/static/`.
+    PathPrefix(String),
+    /// Match requests whose path matches this glob pattern, in which `?` matches any character
+    /// but `/`, `*` matches any run of characters but `/`, and `**` matches any run of characters,
+    /// e.g. `/assets/**/*.png`.
+    PathGlob(String),
+    /// Match requests with this method, e.g. `Method::Options`.
+    Method(Method),
+}
+
+impl RequestFilter {
+    /// Whether a request with the given method and path matches this rule.
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        match *self {
+            RequestFilter::Path(ref exact) => path == exact,
+            RequestFilter::PathPrefix(ref prefix) => path.starts_with(&prefix[..]),
+            RequestFilter::PathGlob(ref pattern) => glob_match(pattern.as_bytes(), path.as_bytes(), Some(b'/')),
+            RequestFilter::Method(ref filtered) => method == filtered,
+        }
+    }
+}
+
+/// Match `text` against a glob `pattern`, in which `?` matches any single
+/// character and `*` any run of characters, except for `separator`; `**`
+/// matches across separators too, and `**/` may match nothing. Both are
+/// compared byte by byte.
+pub fn glob_match(pattern: &[u8], text: &[u8], separator: Option<u8>) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(&b'*') => {
+            let crosses = pattern.get(1) == Some(&b'*');
+            let rest = if crosses { &pattern[2..] } else { &pattern[1..] };
+
+            // A `**/` may also match no directories at all.
+            if crosses && rest.first().map(|&c| Some(c)) == Some(separator) &&
+                glob_match(&rest[1..], text, separator) {
+                return true;
+            }
+
+            // Try every possible length for the run matched by the star.
+            for skip in 0..text.len() + 1 {
+                if glob_match(rest, &text[skip..], separator) {
+                    return true;
+                }
+                if skip < text.len() && !crosses && Some(text[skip]) == separator {
+                    return false;
+                }
+            }
+            false
+        },
+        Some(&b'?') => match text.first() {
+            Some(&c) if Some(c) != separator => glob_match(&pattern[1..], &text[1..], separator),
+            _ => false,
+        },
+        Some(&p) => match text.first() {
+            Some(&c) if c == p => glob_match(&pattern[1..], &text[1..], separator),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::method::Method;
+
+    use super::{glob_match, RequestFilter};
+
+    fn path_glob(pattern: &str, path: &str) -> bool {
+        glob_match(pattern.as_bytes(), path.as_bytes(), Some(b'/'))
+    }
+
+    #[test]
+    fn double_star_slash_matches_zero_or_more_directories() {
+        assert!(path_glob("/assets/**/*.png", "/assets/c.png"));
+        assert!(path_glob("/assets/**/*.png", "/assets/a/c.png"));
+        assert!(path_glob("/assets/**/*.png", "/assets/a/b/c.png"));
+        assert!(!path_glob("/assets/**/*.png", "/assets/a/b/c.jpg"));
+        assert!(!path_glob("/assets/**/*.png", "/other/c.png"));
+        assert!(path_glob("/api/**", "/api/"));
+        assert!(path_glob("/api/**", "/api/v1/users/7"));
+    }
+
+    #[test]
+    fn star_does_not_cross_separators() {
+        assert!(path_glob("/users/*", "/users/7"));
+        assert!(path_glob("/users/*", "/users/"));
+        assert!(!path_glob("/users/*", "/users/7/posts"));
+        assert!(path_glob("/users/*/posts", "/users/7/posts"));
+        assert!(!path_glob("/users/*/posts", "/users/7/8/posts"));
+        assert!(path_glob("/*.css", "/a.b.css"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character_but_not_the_separator() {
+        assert!(path_glob("/v?/users", "/v1/users"));
+        assert!(!path_glob("/v?/users", "/v/users"));
+        assert!(!path_glob("/v?/users", "/v10/users"));
+        assert!(!path_glob("/a?b", "/a/b"));
+    }
+
+    #[test]
+    fn without_a_separator_stars_match_anything() {
+        assert!(glob_match(b"*token*", b"access_token", None));
+        assert!(glob_match(b"*token*", b"token", None));
+        assert!(glob_match(b"x-*-secret", b"x-a/b-secret", None));
+        assert!(glob_match(b"a?c", b"a/c", None));
+        assert!(!glob_match(b"*token*", b"tokn", None));
+        assert!(!glob_match(b"password", b"password2", None));
+    }
+
+    #[test]
+    fn request_filters_match() {
+        let get = Method::Get;
+        assert!(RequestFilter::Path("/healthz".to_owned()).matches(&get, "/healthz"));
+        assert!(!RequestFilter::Path("/healthz".to_owned()).matches(&get, "/healthz/deep"));
+        assert!(RequestFilter::PathPrefix("/static/".to_owned()).matches(&get, "/static/app.js"));
+        assert!(RequestFilter::PathGlob("/static/**/*.js".to_owned()).matches(&get, "/static/app.js"));
+        assert!(RequestFilter::Method(Method::Get).matches(&get, "/"));
+        assert!(!RequestFilter::Method(Method::Post).matches(&get, "/"));
+    }
+}
@@ -1,10 +1,15 @@
 //! Formatting helpers for the logger middleware.
 
 use std::default::Default;
-use std::str::Chars;
+use std::error::Error;
+use std::fmt;
+use std::str::CharIndices;
 use std::iter::Peekable;
 
-use self::FormatText::{Method, URI, Status, ResponseTime, RemoteAddr, RequestTime};
+use time;
+
+use self::FormatText::{Method, URI, Status, ResponseTime, RemoteAddr, RequestTime, RequestHeader,
+                       ResponseHeader, RemoteIp, ClientIp, RemoteUser, RequestLine, StatusCode, BytesSent};
 
 /// A formatting style for the `Logger`, consisting of multiple
 /// `FormatUnit`s concatenated into one line.
@@ -26,29 +31,269 @@ impl Default for Format {
 impl Format {
     /// Create a `Format` from a format string, which can contain the fields
     /// `{method}`, `{uri}`, `{status}`, `{response-time}`, `{ip-addr}` and
-    /// `{request-time}`.
+    /// `{request-time}`, as well as `{req-header:Name}` and `{res-header:Name}`
+    /// to log the named request or response header.
+    ///
+    /// The fields used by the Common Log Format are also available:
+    /// `{remote-ip}` (the client address without port), `{remote-user}` (the
+    /// HTTP Basic username), `{request-line}` (e.g. `GET /index.html HTTP/1.1`),
+    /// `{status-code}` (the bare numeric status), `{bytes-sent}` (the
+    /// `Content-Length` of the response, or the number of body bytes actually
+    /// written when using `LoggerBuilder::log_after_body`) and `{request-time:clf}` (e.g.
+    /// `10/Oct/2000:13:55:36 -0700`). These, and header placeholders, render
+    /// as `-` when the value is unknown; header placeholders can choose their
+    /// own fallback with `{req-header:Name:fallback}`. Repeated headers are
+    /// joined with `, `. The values of sensitive headers, such as
+    /// `Authorization` and `Cookie`, are masked; see
+    /// `LoggerBuilder::mask_header`.
+    ///
+    /// `{client-ip}` logs the address of the client, found by following the
+    /// header chosen with `LoggerBuilder::forwarded_header` through the
+    /// proxies trusted with `LoggerBuilder::trusted_proxy`.
+    ///
+    /// `{ip-addr:anon}`, `{remote-ip:anon}` and `{client-ip:anon}` anonymise
+    /// the address as configured with `LoggerBuilder::anonymise_ips`, or by
+    /// truncating it if that is not configured: `192.0.2.123` is logged as
+    /// `192.0.2.0`.
+    ///
+    /// `{uri}` and `{request-line}` include the query string, in which the
+    /// values of parameters configured with `LoggerBuilder::redact_query_param`
+    /// are replaced with `[REDACTED]`.
+    ///
+    /// `{request-id}` logs the id the logger assigned to the request when
+    /// configured with `LoggerBuilder::request_id`, or `-`.
+    ///
+    /// The response time can be given a unit, one of `ns`, `us`, `ms` (the
+    /// default) or `s`, a number of decimal places, and `raw` to leave out
+    /// the unit suffix, separated by colons in any order: `{response-time:us}`
+    /// renders as `1234.567 us`, `{response-time:s:3}` as `0.001 s` and
+    /// `{response-time:ms:0:raw}` as `1`.
+    ///
+    /// The request time is rendered as ISO 8601 in local time by default,
+    /// e.g. `2000-10-10T13:55:36.123-07:00`. It can be given a preset, one of
+    /// `iso8601`, `rfc3339`, `clf`, `epoch` (seconds since the Unix epoch) or
+    /// `epoch-ms` (milliseconds since the Unix epoch), or a `strftime` pattern
+    /// such as `{request-time:%d/%m/%Y %H:%M}`, optionally preceded by `utc` or
+    /// `local`: `{request-time:utc:rfc3339}` renders as `2000-10-10T20:55:36Z`.
     ///
-    /// Returns `None` if the format string syntax is incorrect.
-    pub fn new(s: &str) -> Option<Format> {
+    /// Literal braces are written as `{{` and `}}`.
+    ///
+    /// Returns a `FormatError` describing the first problem found if the
+    /// format string syntax is incorrect.
+    pub fn new(s: &str) -> Result<Format, FormatError> {
 
-        let parser = FormatParser::new(s.chars().peekable());
+        let parser = FormatParser::new(s);
 
         let mut results = Vec::new();
 
         for unit in parser {
-            match unit {
-                Some(unit) => results.push(unit),
-                None => return None
-            }
+            results.push(try!(unit));
         }
 
-        Some(Format(results))
+        Ok(Format(results))
+    }
+
+    /// Return the NCSA Common Log Format used by Apache's `common` log:
+    ///
+    /// ```ignore
+    /// {remote-ip} - {remote-user} [{request-time:clf}] "{request-line}" {status-code} {bytes-sent}
+    /// // This will be written as:
+    /// // 127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326
+    /// ```
+    pub fn common() -> Format {
+        Format::new(COMMON_FORMAT).unwrap()
+    }
+
+    /// Return the Apache Combined Log Format, which is the Common Log Format
+    /// followed by the quoted `Referer` and `User-Agent` request headers. As
+    /// in Apache's logs, `"`, `\` and control characters in the request line,
+    /// user name and headers are escaped with a backslash in text output:
+    ///
+    /// ```ignore
+    /// {remote-ip} - {remote-user} [{request-time:clf}] "{request-line}" {status-code} {bytes-sent} "{req-header:Referer}" "{req-header:User-Agent}"
+    /// ```
+    pub fn combined() -> Format {
+        Format::new(&format!("{} \"{{req-header:Referer}}\" \"{{req-header:User-Agent}}\"", COMMON_FORMAT)).unwrap()
+    }
+}
+
+static COMMON_FORMAT: &'static str =
+    "{remote-ip} - {remote-user} [{request-time:clf}] \"{request-line}\" {status-code} {bytes-sent}";
+
+// The `strftime` pattern rendered by `{request-time:clf}`.
+const CLF_TIME_FORMAT: &'static str = "%d/%b/%Y:%H:%M:%S %z";
+
+/// The names of all placeholders understood by `Format::new`.
+const PLACEHOLDERS: &'static [&'static str] = &[
+    "method",
+    "uri",
+    "status",
+    "response-time",
+    "request-time",
+    "ip-addr",
+    "remote-ip",
+    "client-ip",
+    "remote-user",
+    "request-line",
+    "status-code",
+    "bytes-sent",
+    "request-id",
+    "req-header",
+    "res-header",
+];
+
+/// The kind of problem encountered while parsing a format string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatErrorKind {
+    /// A placeholder names a field that the `Logger` does not know about.
+    UnknownPlaceholder,
+    /// A `{` was opened but never closed by a matching `}`.
+    UnterminatedPlaceholder,
+    /// A placeholder contains no name at all, as in `{}`.
+    EmptyPlaceholder,
+    /// A placeholder is missing a required argument, or was given one it
+    /// does not accept or cannot understand.
+    InvalidArgument,
+    /// A `}` appears outside of a placeholder without being escaped as `}}`.
+    UnmatchedBrace,
+}
+
+/// An error returned by `Format::new` when a format string cannot be parsed.
+///
+/// The `Display` implementation echoes the format string with a caret under
+/// the offending column, e.g.
+///
+/// ```ignore
+/// unknown placeholder `{respone-time}` at byte 9 (did you mean `{response-time}`?)
+///     {method} {respone-time}
+///              ^
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatError {
+    kind: FormatErrorKind,
+    offset: usize,
+    token: String,
+    suggestion: Option<&'static str>,
+    source: String,
+}
+
+impl FormatError {
+    fn new(kind: FormatErrorKind, source: &str, offset: usize, token: &str) -> FormatError {
+        let suggestion = match kind {
+            FormatErrorKind::UnknownPlaceholder => closest_placeholder(placeholder_name(token).0),
+            _ => None,
+        };
+
+        FormatError {
+            kind: kind,
+            offset: offset,
+            token: token.to_owned(),
+            suggestion: suggestion,
+            source: source.to_owned(),
+        }
+    }
+
+    /// The kind of parse failure.
+    pub fn kind(&self) -> FormatErrorKind {
+        self.kind
+    }
+
+    /// The byte offset in the format string at which the offending token starts.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The offending token, without its surrounding braces.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The known placeholder name closest to the offending token, if any is
+    /// close enough to be a plausible typo.
+    pub fn suggestion(&self) -> Option<&'static str> {
+        self.suggestion
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FormatErrorKind::UnknownPlaceholder =>
+                try!(write!(f, "unknown placeholder `{{{}}}` at byte {}", self.token, self.offset)),
+            FormatErrorKind::UnterminatedPlaceholder =>
+                try!(write!(f, "unterminated placeholder `{{{}` at byte {}", self.token, self.offset)),
+            FormatErrorKind::EmptyPlaceholder =>
+                try!(write!(f, "empty placeholder `{{}}` at byte {}", self.offset)),
+            FormatErrorKind::InvalidArgument =>
+                try!(write!(f, "invalid argument in placeholder `{{{}}}` at byte {}", self.token, self.offset)),
+            FormatErrorKind::UnmatchedBrace =>
+                try!(write!(f, "unmatched `}}` at byte {} (use `}}}}` for a literal brace)", self.offset)),
+        }
+
+        if let Some(suggestion) = self.suggestion {
+            try!(write!(f, " (did you mean `{{{}}}`?)", suggestion));
+        }
+
+        // Count characters rather than bytes so the caret lines up with
+        // multi-byte text preceding the error.
+        let column = self.source[..self.offset].chars().count();
+        try!(write!(f, "\n    {}\n    ", self.source));
+        for _ in 0..column {
+            try!(f.write_str(" "));
+        }
+        f.write_str("^")
+    }
+}
+
+impl Error for FormatError {
+    fn description(&self) -> &str {
+        match self.kind {
+            FormatErrorKind::UnknownPlaceholder => "unknown placeholder in format string",
+            FormatErrorKind::UnterminatedPlaceholder => "unterminated placeholder in format string",
+            FormatErrorKind::EmptyPlaceholder => "empty placeholder in format string",
+            FormatErrorKind::InvalidArgument => "invalid placeholder argument in format string",
+            FormatErrorKind::UnmatchedBrace => "unmatched closing brace in format string",
+        }
+    }
+}
+
+// Find the known placeholder with the smallest edit distance to `token`,
+// provided it is close enough to be a plausible typo.
+fn closest_placeholder(token: &str) -> Option<&'static str> {
+    let max_distance = ::std::cmp::max(2, token.chars().count() / 3);
+
+    PLACEHOLDERS.iter()
+        .map(|&name| (edit_distance(token, name), name))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name)
+}
+
+// Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = ::std::cmp::min(substitution,
+                                             ::std::cmp::min(previous[j + 1], current[j]) + 1);
+        }
+        ::std::mem::swap(&mut previous, &mut current);
     }
+
+    previous[b.len()]
 }
 
 struct FormatParser<'a> {
-    // The characters of the format string.
-    chars: Peekable<Chars<'a>>,
+    // The complete format string, kept for error reporting.
+    source: &'a str,
+
+    // The characters of the format string, with their byte offsets.
+    chars: Peekable<CharIndices<'a>>,
 
     // A reusable buffer for parsing style attributes.
     object_buffer: String,
@@ -61,12 +306,14 @@ struct FormatParser<'a> {
 }
 
 impl<'a> FormatParser<'a> {
-    fn new(chars: Peekable<Chars>) -> FormatParser {
+    fn new(source: &str) -> FormatParser {
         FormatParser {
-            chars: chars,
+            source: source,
+            chars: source.char_indices().peekable(),
 
-            // No attributes are longer than 14 characters, so we can avoid reallocating.
-            object_buffer: String::with_capacity(14),
+            // Room for any placeholder name; arguments such as header names
+            // and `strftime` patterns may still grow it.
+            object_buffer: String::with_capacity(16),
 
             waitqueue: vec![],
             finished: false
@@ -74,16 +321,16 @@ impl<'a> FormatParser<'a> {
     }
 }
 
-// Some(None) means there was a parse error and this FormatParser should be abandoned.
+// Some(Err(_)) means there was a parse error and this FormatParser should be abandoned.
 impl<'a> Iterator for FormatParser<'a> {
-    type Item = Option<FormatUnit>;
+    type Item = Result<FormatUnit, FormatError>;
 
-    fn next(&mut self) -> Option<Option<FormatUnit>> {
+    fn next(&mut self) -> Option<Result<FormatUnit, FormatError>> {
         // If the parser has been cancelled or errored for some reason.
         if self.finished { return None }
 
         if self.waitqueue.len() != 0 {
-            return Some(Some(self.waitqueue.remove(0)));
+            return Some(Ok(self.waitqueue.remove(0)));
         }
 
         // Try to parse a new FormatUnit.
@@ -94,57 +341,66 @@ impl<'a> Iterator for FormatParser<'a> {
             //   - {method}
             //   - {uri}
             //   - {status}
-            //   - {response-time}
-            //   - {ip-addr}
-            //   - {request-time}
-            Some('{') => {
+            //   - {response-time} or {response-time:unit:precision:raw}
+            //   - {ip-addr} or {ip-addr:anon}
+            //   - {request-time} or {request-time:utc-or-local:preset-or-strftime}
+            //   - {remote-ip} or {remote-ip:anon}
+            //   - {client-ip} or {client-ip:anon}
+            //   - {remote-user}
+            //   - {request-line}
+            //   - {status-code}
+            //   - {bytes-sent}
+            //   - {request-id}
+            //   - {req-header:Name} or {req-header:Name:fallback}
+            //   - {res-header:Name} or {res-header:Name:fallback}
+            //
+            // A doubled `{{` is an escaped literal brace instead.
+            Some((start, '{')) => {
+                if let Some(&(_, '{')) = self.chars.peek() {
+                    self.chars.next();
+                    return Some(Ok(self.parse_str(String::from("{"))));
+                }
+
                 self.object_buffer.clear();
 
-                let mut chr = self.chars.next();
-                while chr != None {
-                    match chr.unwrap() {
+                let mut terminated = false;
+                while let Some((_, c)) = self.chars.next() {
+                    match c {
                         // Finished parsing, parse buffer.
-                        '}' => break,
-                        c => self.object_buffer.push(c.clone())
+                        '}' => {
+                            terminated = true;
+                            break;
+                        },
+                        c => self.object_buffer.push(c)
                     }
+                }
 
-                    chr = self.chars.next();
+                if !terminated {
+                    return Some(Err(self.error(FormatErrorKind::UnterminatedPlaceholder, start)));
                 }
 
-                let text = match self.object_buffer.as_ref() {
-                    "method" => Method,
-                    "uri" => URI,
-                    "status" => Status,
-                    "response-time" => ResponseTime,
-                    "request-time" => RequestTime,
-                    "ip-addr" => RemoteAddr,
-                    _ => {
-                        // Error, so mark as finished.
-                        self.finished = true;
-                        return Some(None);
-                    }
-                };
+                match parse_placeholder(&self.object_buffer) {
+                    Ok(text) => Some(Ok(FormatUnit {text: text})),
+                    Err(kind) => Some(Err(self.error(kind, start)))
+                }
+            },
+
+            // A `}` outside of a placeholder must be escaped as `}}`.
+            Some((start, '}')) => {
+                if let Some(&(_, '}')) = self.chars.peek() {
+                    self.chars.next();
+                    return Some(Ok(self.parse_str(String::from("}"))));
+                }
 
-                Some(Some(FormatUnit {text: text}))
+                Some(Err(self.error(FormatErrorKind::UnmatchedBrace, start)))
             },
 
             // Parse a regular string part of the format string.
-            Some(c) => {
+            Some((_, c)) => {
                 let mut buffer = String::new();
                 buffer.push(c);
 
-                loop {
-                    match self.chars.peek() {
-                        // Done parsing.
-                        Some(&'{') | None => {
-                            return Some(Some(FormatUnit {text: FormatText::Str(buffer)}))
-                        },
-
-                        Some(_) => {
-                            buffer.push(self.chars.next().unwrap())
-                        }
-                    }
-                }
+                Some(Ok(self.parse_str(buffer)))
             },
 
             // Reached end of the format string.
@@ -153,6 +409,168 @@ impl<'a> Iterator for FormatParser<'a> {
     }
 }
 
+// Split the contents of a placeholder into its name and the argument
+// following the first `:`, if any.
+fn placeholder_name(contents: &str) -> (&str, Option<&str>) {
+    match contents.find(':') {
+        Some(colon) => (&contents[..colon], Some(&contents[colon + 1..])),
+        None => (contents, None)
+    }
+}
+
+// Parse the contents of a `{...}` placeholder into the `FormatText` it names.
+fn parse_placeholder(contents: &str) -> Result<FormatText, FormatErrorKind> {
+    match placeholder_name(contents) {
+        ("", None) => Err(FormatErrorKind::EmptyPlaceholder),
+        ("method", None) => Ok(Method),
+        ("uri", None) => Ok(URI),
+        ("status", None) => Ok(Status),
+        ("response-time", None) => Ok(ResponseTime(DurationFormat::default())),
+        ("response-time", Some(arg)) => parse_duration_argument(arg).map(ResponseTime),
+        ("request-time", None) => Ok(RequestTime(TimeFormat::default())),
+        ("request-time", Some(arg)) => parse_time_argument(arg).map(RequestTime),
+        ("ip-addr", None) => Ok(RemoteAddr { anonymise: false }),
+        ("ip-addr", Some("anon")) => Ok(RemoteAddr { anonymise: true }),
+        ("remote-ip", None) => Ok(RemoteIp { anonymise: false }),
+        ("remote-ip", Some("anon")) => Ok(RemoteIp { anonymise: true }),
+        ("client-ip", None) => Ok(ClientIp { anonymise: false }),
+        ("client-ip", Some("anon")) => Ok(ClientIp { anonymise: true }),
+        ("remote-user", None) => Ok(RemoteUser),
+        ("request-line", None) => Ok(RequestLine),
+        ("status-code", None) => Ok(StatusCode),
+        ("bytes-sent", None) => Ok(BytesSent),
+        ("request-id", None) => Ok(FormatText::RequestId),
+        ("req-header", Some(arg)) => {
+            let (name, fallback) = try!(parse_header_argument(arg));
+            Ok(RequestHeader { name: name, fallback: fallback })
+        },
+        ("res-header", Some(arg)) => {
+            let (name, fallback) = try!(parse_header_argument(arg));
+            Ok(ResponseHeader { name: name, fallback: fallback })
+        },
+        (name, _) if PLACEHOLDERS.contains(&name) => Err(FormatErrorKind::InvalidArgument),
+        _ => Err(FormatErrorKind::UnknownPlaceholder)
+    }
+}
+
+// Parse the argument of a `{request-time:...}` placeholder: an optional
+// `utc` or `local` segment followed by a preset name or a `strftime` pattern.
+fn parse_time_argument(arg: &str) -> Result<TimeFormat, FormatErrorKind> {
+    let (utc, style) = match placeholder_name(arg) {
+        ("utc", style) => (true, style.unwrap_or("iso8601")),
+        ("local", style) => (false, style.unwrap_or("iso8601")),
+        _ => (false, arg),
+    };
+
+    let style = match style {
+        "iso8601" => TimeStyle::Iso8601,
+        "rfc3339" => TimeStyle::Rfc3339,
+        "clf" => TimeStyle::Strftime(CLF_TIME_FORMAT.to_owned()),
+        "epoch" => TimeStyle::Epoch,
+        "epoch-ms" => TimeStyle::EpochMs,
+        // Anything else must be a valid `strftime` pattern. Requiring a `%`
+        // catches misspelt preset names, which would otherwise be printed
+        // verbatim.
+        pattern if pattern.contains('%') && time::empty_tm().strftime(pattern).is_ok() => {
+            TimeStyle::Strftime(pattern.to_owned())
+        },
+        _ => return Err(FormatErrorKind::InvalidArgument)
+    };
+
+    Ok(TimeFormat { style: style, utc: utc })
+}
+
+// Parse the `unit`, `precision` and `raw` segments of a `{response-time:...}`
+// argument, in any order.
+fn parse_duration_argument(arg: &str) -> Result<DurationFormat, FormatErrorKind> {
+    let mut format = DurationFormat::default();
+    let (mut unit, mut precision, mut raw) = (false, false, false);
+
+    for segment in arg.split(':') {
+        let seen = match segment {
+            "ns" => { format.unit = DurationUnit::Nanoseconds; &mut unit },
+            "us" => { format.unit = DurationUnit::Microseconds; &mut unit },
+            "ms" => { format.unit = DurationUnit::Milliseconds; &mut unit },
+            "s" => { format.unit = DurationUnit::Seconds; &mut unit },
+            "raw" => { format.raw = true; &mut raw },
+            digits => match digits.parse() {
+                Ok(digits) if digits <= 9 => { format.precision = Some(digits); &mut precision },
+                _ => return Err(FormatErrorKind::InvalidArgument)
+            }
+        };
+
+        // Each kind of segment may only be given once.
+        if *seen {
+            return Err(FormatErrorKind::InvalidArgument);
+        }
+        *seen = true;
+    }
+
+    Ok(format)
+}
+
+// Parse the `Name` or `Name:fallback` argument of a header placeholder.
+// Header values that are absent render as the fallback, `-` by default.
+fn parse_header_argument(arg: &str) -> Result<(String, String), FormatErrorKind> {
+    let (name, fallback) = placeholder_name(arg);
+
+    let valid_name = !name.is_empty() && name.chars().all(|c| {
+        c.is_ascii() && (c.is_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    });
+    if !valid_name {
+        return Err(FormatErrorKind::InvalidArgument);
+    }
+
+    Ok((name.to_owned(), fallback.unwrap_or("-").to_owned()))
+}
+
+impl<'a> FormatParser<'a> {
+    // Continue a regular string part of the format string, unescaping any
+    // `{{` and `}}` pairs, until a placeholder, stray brace or the end.
+    fn parse_str(&mut self, mut buffer: String) -> FormatUnit {
+        loop {
+            match self.chars.peek() {
+                // Possibly an escaped brace; look one character further.
+                Some(&(_, brace @ '{')) | Some(&(_, brace @ '}')) => {
+                    let mut lookahead = self.chars.clone();
+                    lookahead.next();
+
+                    match lookahead.next() {
+                        Some((_, c)) if c == brace => {
+                            self.chars.next();
+                            self.chars.next();
+                            buffer.push(brace);
+                        },
+
+                        // Done parsing; the brace is handled by the next unit.
+                        _ => return FormatUnit {text: FormatText::Str(buffer)}
+                    }
+                },
+
+                // Done parsing.
+                None => return FormatUnit {text: FormatText::Str(buffer)},
+
+                Some(_) => {
+                    buffer.push(self.chars.next().unwrap().1)
+                }
+            }
+        }
+    }
+
+    // Build an error for the token starting at byte `start`, marking
+    // the parser as finished so that no further units are produced.
+    fn error(&mut self, kind: FormatErrorKind, start: usize) -> FormatError {
+        self.finished = true;
+
+        let token = match kind {
+            FormatErrorKind::UnmatchedBrace => "}",
+            _ => &self.object_buffer
+        };
+
+        FormatError::new(kind, self.source, start, token)
+    }
+}
+
 /// A string of text to be logged. This is either one of the data
 /// fields supported by the `Logger`, or a custom `String`.
 #[derive(Clone)]
@@ -162,9 +580,127 @@ pub enum FormatText {
     Method,
     URI,
     Status,
-    ResponseTime,
-    RemoteAddr,
-    RequestTime
+    ResponseTime(DurationFormat),
+    RemoteAddr { anonymise: bool },
+    RequestTime(TimeFormat),
+    RemoteIp { anonymise: bool },
+    ClientIp { anonymise: bool },
+    RemoteUser,
+    RequestLine,
+    StatusCode,
+    BytesSent,
+    RequestId,
+    RequestHeader { name: String, fallback: String },
+    ResponseHeader { name: String, fallback: String }
+}
+
+/// How a `{request-time}` placeholder renders the time a request arrived.
+#[derive(Clone, Default)]
+#[doc(hidden)]
+pub struct TimeFormat {
+    pub style: TimeStyle,
+    pub utc: bool,
+}
+
+impl TimeFormat {
+    /// Render the time `tm`, in UTC or local time as chosen.
+    pub fn render(&self, tm: &time::Tm) -> String {
+        let tm = if self.utc { tm.to_utc() } else { tm.to_local() };
+        self.style.render(&tm)
+    }
+}
+
+impl TimeStyle {
+    // Render `tm` in its own time zone.
+    fn render(&self, tm: &time::Tm) -> String {
+        match *self {
+            TimeStyle::Strftime(ref pattern) => format!("{}", tm.strftime(pattern).unwrap()),
+            TimeStyle::Iso8601 => {
+                format!("{}.{:03}{}", tm.strftime("%Y-%m-%dT%H:%M:%S").unwrap(), tm.tm_nsec / 1000000,
+                        utc_offset(tm))
+            },
+            TimeStyle::Rfc3339 => format!("{}{}", tm.strftime("%Y-%m-%dT%H:%M:%S").unwrap(), utc_offset(tm)),
+            TimeStyle::Epoch => format!("{}", tm.to_timespec().sec),
+            TimeStyle::EpochMs => {
+                let timespec = tm.to_timespec();
+                format!("{}", timespec.sec * 1000 + (timespec.nsec / 1000000) as i64)
+            },
+        }
+    }
+}
+
+// The offset of `tm` from UTC as `Z` or `+hh:mm`.
+fn utc_offset(tm: &time::Tm) -> String {
+    if tm.tm_utcoff == 0 {
+        return "Z".to_owned();
+    }
+
+    let minutes = tm.tm_utcoff.abs() / 60;
+    format!("{}{:02}:{:02}", if tm.tm_utcoff < 0 { '-' } else { '+' }, minutes / 60, minutes % 60)
+}
+
+/// The style of a `{request-time}` placeholder.
+#[derive(Clone)]
+#[doc(hidden)]
+pub enum TimeStyle {
+    Strftime(String),
+    Iso8601,
+    Rfc3339,
+    Epoch,
+    EpochMs,
+}
+
+impl Default for TimeStyle {
+    fn default() -> TimeStyle {
+        TimeStyle::Iso8601
+    }
+}
+
+/// How a `{response-time}` placeholder renders a duration.
+#[derive(Clone, Default)]
+#[doc(hidden)]
+pub struct DurationFormat {
+    pub unit: DurationUnit,
+    pub precision: Option<usize>,
+    pub raw: bool,
+}
+
+impl DurationFormat {
+    /// Render a duration of `ns` nanoseconds.
+    pub fn render(&self, ns: u64) -> String {
+        let (divisor, suffix) = match self.unit {
+            DurationUnit::Nanoseconds => (1.0, " ns"),
+            DurationUnit::Microseconds => (1000.0, " us"),
+            DurationUnit::Milliseconds => (1000000.0, " ms"),
+            DurationUnit::Seconds => (1000000000.0, " s"),
+        };
+        let value = ns as f64 / divisor;
+
+        let mut rendered = match self.precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => format!("{}", value),
+        };
+        if !self.raw {
+            rendered.push_str(suffix);
+        }
+        rendered
+    }
+}
+
+/// The unit of a `{response-time}` placeholder.
+#[derive(Clone, Copy)]
+#[doc(hidden)]
+pub enum DurationUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Default for DurationUnit {
+    fn default() -> DurationUnit {
+        DurationUnit::Milliseconds
+    }
 }
 
 /// A `FormatText` with associated style information.
@@ -173,3 +709,276 @@ pub enum FormatText {
 pub struct FormatUnit {
     pub text: FormatText,
 }
+
+#[cfg(test)]
+mod tests {
+    use time::Tm;
+
+    use super::{parse_duration_argument, parse_time_argument, DurationFormat, Format, FormatError, FormatErrorKind, FormatText, TimeFormat, TimeStyle,
+                CLF_TIME_FORMAT};
+
+    // 10 October 2000, 13:55:36.123 at UTC-7, the time in Apache's examples.
+    fn apache_time() -> Tm {
+        Tm {
+            tm_sec: 36, tm_min: 55, tm_hour: 13,
+            tm_mday: 10, tm_mon: 9, tm_year: 100,
+            tm_wday: 2, tm_yday: 283, tm_isdst: 0,
+            tm_utcoff: -7 * 3600, tm_nsec: 123000000,
+        }
+    }
+
+    // The same moment in UTC. `Tm::to_timespec` only honours the offset of
+    // times in UTC or the system time zone, so epochs are checked with this.
+    fn apache_time_utc() -> Tm {
+        Tm { tm_hour: 20, tm_utcoff: 0, ..apache_time() }
+    }
+
+    // The placeholders and literal text of a format, in order.
+    fn tokens(format: &Format) -> Vec<String> {
+        format.0.iter().map(|unit| match unit.text {
+            FormatText::Str(ref text) => text.clone(),
+            FormatText::RemoteIp { anonymise: false } => "{remote-ip}".to_owned(),
+            FormatText::RemoteUser => "{remote-user}".to_owned(),
+            FormatText::RequestTime(ref time_format) => match time_format.style {
+                TimeStyle::Strftime(ref pattern) if pattern == CLF_TIME_FORMAT && !time_format.utc =>
+                    "{request-time:clf}".to_owned(),
+                _ => "{request-time:?}".to_owned(),
+            },
+            FormatText::RequestLine => "{request-line}".to_owned(),
+            FormatText::StatusCode => "{status-code}".to_owned(),
+            FormatText::BytesSent => "{bytes-sent}".to_owned(),
+            FormatText::RequestHeader { ref name, ref fallback } => format!("{{req-header:{}:{}}}", name, fallback),
+            _ => "{?}".to_owned(),
+        }).collect()
+    }
+
+    // The literal text of a format, with placeholders written as `<name>`.
+    fn shape(s: &str) -> String {
+        let format = match Format::new(s) {
+            Ok(format) => format,
+            Err(err) => panic!("{:?} did not parse: {}", s, err),
+        };
+
+        format.0.iter().map(|unit| match unit.text {
+            FormatText::Str(ref text) => text.clone(),
+            FormatText::Method => "<method>".to_owned(),
+            _ => "<placeholder>".to_owned(),
+        }).collect()
+    }
+
+    fn error(s: &str) -> FormatError {
+        match Format::new(s) {
+            Ok(_) => panic!("{:?} parsed without error", s),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn reports_unknown_placeholders_with_a_suggestion() {
+        let err = error("{method} {respone-time}");
+        assert_eq!(err.kind(), FormatErrorKind::UnknownPlaceholder);
+        assert_eq!(err.offset(), 9);
+        assert_eq!(err.token(), "respone-time");
+        assert_eq!(err.suggestion(), Some("response-time"));
+        assert_eq!(err.to_string(),
+                   "unknown placeholder `{respone-time}` at byte 9 (did you mean `{response-time}`?)\n\
+                    \x20   {method} {respone-time}\n\
+                    \x20            ^");
+    }
+
+    #[test]
+    fn suggests_only_plausible_typos() {
+        assert_eq!(error("{methd}").suggestion(), Some("method"));
+        assert_eq!(error("{req-heder:Host}").suggestion(), Some("req-header"));
+        assert_eq!(error("{xyzzy}").suggestion(), None);
+    }
+
+    #[test]
+    fn lines_the_caret_up_with_multi_byte_text() {
+        let err = error("héllo → {nope}");
+        assert_eq!(err.kind(), FormatErrorKind::UnknownPlaceholder);
+        assert_eq!(err.offset(), 11);
+        assert!(err.to_string().ends_with("\n    héllo → {nope}\n            ^"), "{}", err);
+    }
+
+    #[test]
+    fn reports_unterminated_placeholders() {
+        let err = error("{method} {uri");
+        assert_eq!(err.kind(), FormatErrorKind::UnterminatedPlaceholder);
+        assert_eq!(err.offset(), 9);
+        assert_eq!(err.token(), "uri");
+    }
+
+    #[test]
+    fn reports_empty_placeholders() {
+        let err = error("{method} {}");
+        assert_eq!(err.kind(), FormatErrorKind::EmptyPlaceholder);
+        assert_eq!(err.offset(), 9);
+    }
+
+    #[test]
+    fn reports_invalid_arguments() {
+        for s in &["{req-header}", "{req-header:}", "{method:x}", "{response-time:ms:us}",
+                   "{response-time:10}", "{request-time:isoo8601}", "{ip-addr:anonymous}"] {
+            let err = error(s);
+            assert_eq!(err.kind(), FormatErrorKind::InvalidArgument, "{}", s);
+            assert_eq!(err.offset(), 0, "{}", s);
+        }
+    }
+
+    #[test]
+    fn reports_the_first_error() {
+        let err = error("{uri} {nope} {");
+        assert_eq!(err.kind(), FormatErrorKind::UnknownPlaceholder);
+        assert_eq!(err.offset(), 6);
+    }
+
+    #[test]
+    fn parses_escaped_braces() {
+        assert_eq!(shape("{{"), "{");
+        assert_eq!(shape("}}"), "}");
+        assert_eq!(shape("a {{b}} c"), "a {b} c");
+        assert_eq!(shape("{{{method}}}"), "{<method>}");
+        assert_eq!(shape("{method}}}"), "<method>}");
+        assert_eq!(shape("{{}}"), "{}");
+    }
+
+    #[test]
+    fn reports_stray_closing_braces() {
+        let err = error("a } b");
+        assert_eq!(err.kind(), FormatErrorKind::UnmatchedBrace);
+        assert_eq!(err.offset(), 2);
+        assert_eq!(err.token(), "}");
+
+        let err = error("{method}}");
+        assert_eq!(err.kind(), FormatErrorKind::UnmatchedBrace);
+        assert_eq!(err.offset(), 8);
+
+        let err = error("a }}} b");
+        assert_eq!(err.kind(), FormatErrorKind::UnmatchedBrace);
+        assert_eq!(err.offset(), 4);
+    }
+
+    #[test]
+    fn renders_clf_times() {
+        assert_eq!(TimeStyle::Strftime(CLF_TIME_FORMAT.to_owned()).render(&apache_time()),
+                   "10/Oct/2000:13:55:36 -0700");
+    }
+
+    #[test]
+    fn builds_the_common_log_format() {
+        assert_eq!(tokens(&Format::common()),
+                   ["{remote-ip}", " - ", "{remote-user}", " [", "{request-time:clf}", "] \"", "{request-line}",
+                    "\" ", "{status-code}", " ", "{bytes-sent}"]);
+    }
+
+    #[test]
+    fn builds_the_combined_log_format() {
+        assert_eq!(tokens(&Format::combined()),
+                   ["{remote-ip}", " - ", "{remote-user}", " [", "{request-time:clf}", "] \"", "{request-line}",
+                    "\" ", "{status-code}", " ", "{bytes-sent}", " \"", "{req-header:Referer:-}", "\" \"",
+                    "{req-header:User-Agent:-}", "\""]);
+    }
+
+    fn time_format(arg: &str) -> TimeFormat {
+        match parse_time_argument(arg) {
+            Ok(time_format) => time_format,
+            Err(kind) => panic!("{:?} did not parse: {:?}", arg, kind),
+        }
+    }
+
+    #[test]
+    fn parses_utc_and_local_prefixes() {
+        assert!(time_format("utc:rfc3339").utc);
+        assert!(!time_format("local:rfc3339").utc);
+        assert!(!time_format("rfc3339").utc);
+
+        // Without a style, the prefix applies to the default ISO 8601 style.
+        let utc = time_format("utc");
+        assert!(utc.utc);
+        assert_eq!(utc.style.render(&apache_time()), "2000-10-10T13:55:36.123-07:00");
+    }
+
+    #[test]
+    fn renders_presets() {
+        let tm = apache_time();
+        assert_eq!(time_format("iso8601").style.render(&tm), "2000-10-10T13:55:36.123-07:00");
+        assert_eq!(time_format("rfc3339").style.render(&tm), "2000-10-10T13:55:36-07:00");
+        assert_eq!(time_format("clf").style.render(&tm), "10/Oct/2000:13:55:36 -0700");
+        assert_eq!(time_format("epoch").style.render(&apache_time_utc()), "971211336");
+        assert_eq!(time_format("epoch-ms").style.render(&apache_time_utc()), "971211336123");
+        assert_eq!(TimeFormat::default().style.render(&tm), "2000-10-10T13:55:36.123-07:00");
+    }
+
+    #[test]
+    fn renders_utc_offsets() {
+        let utc = time_format("utc:rfc3339");
+        assert_eq!(utc.render(&apache_time_utc()), "2000-10-10T20:55:36Z");
+        assert_eq!(time_format("utc:epoch-ms").render(&apache_time_utc()), "971211336123");
+
+        let mut india = apache_time();
+        india.tm_utcoff = 5 * 3600 + 30 * 60;
+        assert_eq!(time_format("rfc3339").style.render(&india), "2000-10-10T13:55:36+05:30");
+    }
+
+    #[test]
+    fn parses_strftime_patterns_with_colons() {
+        let tm = apache_time();
+        let local = time_format("%H:%M");
+        assert!(!local.utc);
+        assert_eq!(local.style.render(&tm), "13:55");
+
+        let utc = time_format("utc:%d/%m/%Y %H:%M:%S");
+        assert!(utc.utc);
+        assert_eq!(utc.render(&apache_time_utc()), "10/10/2000 20:55:36");
+
+        assert_eq!(shape("[{request-time:%H:%M}]"), "[<placeholder>]");
+    }
+
+    #[test]
+    fn rejects_unknown_time_styles() {
+        for arg in &["isoo8601", "H:M", "utc:bogus", "local:", "utc:utc", "rfc3339:utc", ""] {
+            assert_eq!(parse_time_argument(arg).err(), Some(FormatErrorKind::InvalidArgument), "{}", arg);
+        }
+    }
+
+    fn duration_format(arg: &str) -> DurationFormat {
+        match parse_duration_argument(arg) {
+            Ok(duration_format) => duration_format,
+            Err(kind) => panic!("{:?} did not parse: {:?}", arg, kind),
+        }
+    }
+
+    #[test]
+    fn renders_durations_in_units() {
+        assert_eq!(DurationFormat::default().render(1500000), "1.5 ms");
+        assert_eq!(duration_format("ns").render(42), "42 ns");
+        assert_eq!(duration_format("us").render(1234567), "1234.567 us");
+        assert_eq!(duration_format("ms").render(1234567), "1.234567 ms");
+        assert_eq!(duration_format("s").render(2500000000), "2.5 s");
+    }
+
+    #[test]
+    fn renders_durations_with_precision_and_raw() {
+        assert_eq!(duration_format("s:3").render(1234567), "0.001 s");
+        assert_eq!(duration_format("ms:0:raw").render(1234567), "1");
+        assert_eq!(duration_format("2").render(1234567), "1.23 ms");
+        assert_eq!(duration_format("raw").render(1500000), "1.5");
+        assert_eq!(duration_format("us:9").render(1), "0.001000000 us");
+    }
+
+    #[test]
+    fn accepts_duration_segments_in_any_order() {
+        for arg in &["ms:0:raw", "ms:raw:0", "0:ms:raw", "0:raw:ms", "raw:ms:0", "raw:0:ms"] {
+            assert_eq!(duration_format(arg).render(1234567), "1", "{}", arg);
+        }
+        assert_eq!(duration_format("3:us").render(1234567), "1234.567 us");
+    }
+
+    #[test]
+    fn rejects_repeated_or_unknown_duration_segments() {
+        for arg in &["ms:us", "1:2", "raw:raw", "10", "min", "ms:", ""] {
+            assert_eq!(parse_duration_argument(arg).err(), Some(FormatErrorKind::InvalidArgument), "{}", arg);
+        }
+    }
+}
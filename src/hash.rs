@@ -0,0 +1,108 @@
+//! Hashing and pseudo-random numbers.
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use time;
+
+/// The 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// A pseudo-random number, good enough to sample with or to make ids that are
+/// unique in practice, but not for anything that needs to be unpredictable.
+pub fn random() -> u64 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+    let seed = time::precise_time_ns() ^ ((process::id() as u64) << 32) ^ time::get_time().sec as u64;
+    splitmix64(seed ^ splitmix64(count))
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The SipHash-2-4 hash of `bytes`, keyed by `key`. Unlike `fnv1a`, the hash
+/// cannot be reversed or predicted without the key.
+pub fn siphash(key: &[u8; 16], bytes: &[u8]) -> u64 {
+    let k0 = read_u64_le(&key[..8]);
+    let k1 = read_u64_le(&key[8..]);
+    let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d,
+                 k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+
+    let mut chunks = bytes.chunks(8);
+    let mut last = [0u8; 8];
+    for chunk in &mut chunks {
+        if chunk.len() < 8 {
+            last[..chunk.len()].copy_from_slice(chunk);
+            break;
+        }
+        compress(&mut v, read_u64_le(chunk));
+    }
+
+    // The final block holds the remaining bytes and the length of the input.
+    last[7] = bytes.len() as u8;
+    compress(&mut v, read_u64_le(&last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= m;
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]); v[1] = v[1].rotate_left(13); v[1] ^= v[0]; v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]); v[3] = v[3].rotate_left(16); v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]); v[3] = v[3].rotate_left(21); v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]); v[1] = v[1].rotate_left(17); v[1] ^= v[2]; v[2] = v[2].rotate_left(32);
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &byte| (n << 8) | byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::siphash;
+
+    // Outputs from the reference implementation of SipHash-2-4, with the key
+    // `00 01 .. 0f` and the input `00 01 .. (length - 1)`.
+    #[test]
+    fn siphash_matches_reference_vectors() {
+        let mut key = [0u8; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let input: Vec<u8> = (0..64).collect();
+
+        assert_eq!(siphash(&key, &input[..0]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash(&key, &input[..1]), 0x74f839c593dc67fd);
+        assert_eq!(siphash(&key, &input[..7]), 0xab0200f58b01d137);
+        assert_eq!(siphash(&key, &input[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash(&key, &input[..15]), 0xa129ca6149be45e5);
+        assert_eq!(siphash(&key, &input[..63]), 0x958a324ceb064572);
+    }
+
+    #[test]
+    fn siphash_depends_on_the_key() {
+        assert!(siphash(&[0; 16], b"192.0.2.1") != siphash(&[1; 16], b"192.0.2.1"));
+    }
+}
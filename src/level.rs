@@ -0,0 +1,155 @@
+//! Choosing the log level of each line from the response status.
+
+use log::LogLevel;
+
+/// A class of HTTP status codes, identified by the first digit of the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusClass {
+    /// `1xx` statuses.
+    Informational,
+    /// `2xx` statuses.
+    Success,
+    /// `3xx` statuses.
+    Redirection,
+    /// `4xx` statuses.
+    ClientError,
+    /// `5xx` statuses.
+    ServerError,
+}
+
+impl StatusClass {
+    /// The class of the status `code`, or `None` if it is outside of the five classes.
+    pub fn from_code(code: u16) -> Option<StatusClass> {
+        match code / 100 {
+            1 => Some(StatusClass::Informational),
+            2 => Some(StatusClass::Success),
+            3 => Some(StatusClass::Redirection),
+            4 => Some(StatusClass::ClientError),
+            5 => Some(StatusClass::ServerError),
+            _ => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            StatusClass::Informational => 0,
+            StatusClass::Success => 1,
+            StatusClass::Redirection => 2,
+            StatusClass::ClientError => 3,
+            StatusClass::ServerError => 4,
+        }
+    }
+}
+
+/// The log levels used for each status class, individual status codes, and
+/// responses without a status.
+pub struct StatusLevels {
+    classes: [LogLevel; 5],
+    codes: Vec<(u16, LogLevel)>,
+    missing: LogLevel,
+}
+
+impl StatusLevels {
+    /// Log `1xx`, `2xx` and `3xx` responses at info, `4xx` responses and
+    /// responses without a status at warn, and `5xx` responses at error.
+    pub fn new() -> StatusLevels {
+        StatusLevels {
+            classes: [LogLevel::Info, LogLevel::Info, LogLevel::Info, LogLevel::Warn, LogLevel::Error],
+            codes: Vec::new(),
+            missing: LogLevel::Warn,
+        }
+    }
+
+    pub fn set_class(&mut self, class: StatusClass, level: LogLevel) {
+        self.classes[class.index()] = level;
+    }
+
+    pub fn set_code(&mut self, code: u16, level: LogLevel) {
+        self.codes.retain(|&(existing, _)| existing != code);
+        self.codes.push((code, level));
+    }
+
+    pub fn set_missing(&mut self, level: LogLevel) {
+        self.missing = level;
+    }
+
+    /// The level to log a response with the given status code at. Individual
+    /// codes take precedence over their class; codes outside of the five
+    /// classes are treated like a missing status.
+    pub fn level(&self, code: Option<u16>) -> LogLevel {
+        let code = match code {
+            Some(code) => code,
+            None => return self.missing,
+        };
+
+        if let Some(&(_, level)) = self.codes.iter().find(|&&(existing, _)| existing == code) {
+            return level;
+        }
+
+        match StatusClass::from_code(code) {
+            Some(class) => self.classes[class.index()],
+            None => self.missing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+
+    use super::{StatusClass, StatusLevels};
+
+    #[test]
+    fn classifies_status_codes() {
+        assert_eq!(StatusClass::from_code(100), Some(StatusClass::Informational));
+        assert_eq!(StatusClass::from_code(204), Some(StatusClass::Success));
+        assert_eq!(StatusClass::from_code(399), Some(StatusClass::Redirection));
+        assert_eq!(StatusClass::from_code(404), Some(StatusClass::ClientError));
+        assert_eq!(StatusClass::from_code(599), Some(StatusClass::ServerError));
+        assert_eq!(StatusClass::from_code(99), None);
+        assert_eq!(StatusClass::from_code(600), None);
+        assert_eq!(StatusClass::from_code(0), None);
+    }
+
+    #[test]
+    fn uses_default_levels_per_class() {
+        let levels = StatusLevels::new();
+        assert_eq!(levels.level(Some(101)), LogLevel::Info);
+        assert_eq!(levels.level(Some(200)), LogLevel::Info);
+        assert_eq!(levels.level(Some(304)), LogLevel::Info);
+        assert_eq!(levels.level(Some(404)), LogLevel::Warn);
+        assert_eq!(levels.level(Some(500)), LogLevel::Error);
+        assert_eq!(levels.level(None), LogLevel::Warn);
+    }
+
+    #[test]
+    fn prefers_codes_over_their_class() {
+        let mut levels = StatusLevels::new();
+        levels.set_class(StatusClass::ClientError, LogLevel::Info);
+        levels.set_code(404, LogLevel::Debug);
+        levels.set_code(429, LogLevel::Error);
+        levels.set_code(429, LogLevel::Warn);
+
+        assert_eq!(levels.level(Some(404)), LogLevel::Debug);
+        assert_eq!(levels.level(Some(429)), LogLevel::Warn);
+        assert_eq!(levels.level(Some(400)), LogLevel::Info);
+
+        // Setting the class afterwards does not override the code.
+        levels.set_class(StatusClass::ClientError, LogLevel::Error);
+        assert_eq!(levels.level(Some(404)), LogLevel::Debug);
+        assert_eq!(levels.level(Some(400)), LogLevel::Error);
+    }
+
+    #[test]
+    fn treats_codes_outside_the_classes_as_missing() {
+        let mut levels = StatusLevels::new();
+        levels.set_missing(LogLevel::Error);
+        assert_eq!(levels.level(None), LogLevel::Error);
+        assert_eq!(levels.level(Some(600)), LogLevel::Error);
+        assert_eq!(levels.level(Some(42)), LogLevel::Error);
+
+        // Unless the code is given a level of its own.
+        levels.set_code(999, LogLevel::Trace);
+        assert_eq!(levels.level(Some(999)), LogLevel::Trace);
+    }
+}
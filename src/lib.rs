@@ -6,17 +6,55 @@ extern crate iron;
 #[macro_use] extern crate log;
 extern crate time;
 
-use iron::{AfterMiddleware, BeforeMiddleware, IronResult, IronError, Request, Response};
+use std::cmp;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use iron::{AfterMiddleware, AroundMiddleware, BeforeMiddleware, Handler, IronResult, IronError, Request,
+           Response};
+use iron::headers::{Authorization, Basic, ContentLength, Headers};
 use iron::typemap::Key;
+use log::LogLevel;
+
+use format::FormatText::{Str, Method, URI, Status, ResponseTime, RemoteAddr, RequestTime, RequestHeader,
+                         ResponseHeader, RemoteIp, ClientIp, RemoteUser, RequestLine, StatusCode, BytesSent};
+use format::{Format, FormatText, TimeFormat};
+use builder::Config;
+use counting::CountingBody;
+use output::{LogPiece, Value};
 
-use format::FormatText::{Str, Method, URI, Status, ResponseTime, RemoteAddr, RequestTime};
-use format::{Format, FormatText};
+pub use anonymise::IpAnonymisation;
+pub use builder::LoggerBuilder;
+pub use client_ip::{Cidr, ForwardedHeader, ParseCidrError};
+pub use clock::{Clock, SystemClock};
+pub use filter::RequestFilter;
+pub use level::StatusClass;
+pub use output::OutputMode;
+pub use redact::HeaderMask;
+pub use request_id::RequestId;
 
 pub mod format;
+mod anonymise;
+mod builder;
+mod client_ip;
+mod clock;
+mod counting;
+mod filter;
+mod hash;
+mod level;
+mod output;
+mod redact;
+mod request_id;
+mod sampling;
 
 /// `Middleware` for logging request and response info to the terminal.
+///
+/// Cloning a `Logger` is cheap, and the clone shares its configuration and counters.
+#[derive(Clone)]
 pub struct Logger {
-    format: Option<Format>
+    config: Arc<Config>,
 }
 
 impl Logger {
@@ -36,49 +74,299 @@ impl Logger {
     /// // link other middlewares here...
     /// chain.link_after(logger_after);
     /// ```
+    ///
+    /// Alternatively, link a single logger with `Chain::link_around` to time and log everything it
+    /// wraps; see `LoggerBuilder::build_around`. Use a `LoggerBuilder` for any other configuration.
     pub fn new(format: Option<Format>) -> (Logger, Logger) {
-        (Logger { format: format.clone() }, Logger { format: format })
+        match format {
+            Some(format) => LoggerBuilder::new().format(format).build(),
+            None => LoggerBuilder::new().build(),
+        }
     }
+
+    /// The number of requests this logger has seen without a start time, because its
+    /// `BeforeMiddleware` half did not run first. This should always be zero in a correctly
+    /// linked chain, which makes it useful to check in tests:
+    ///
+    /// ```ignore
+    /// let (logger_before, logger_after) = Logger::new(None);
+    /// let probe = logger_after.clone();
+    /// chain.link_before(logger_before);
+    /// chain.link_after(logger_after);
+    /// // ... send some requests through the chain ...
+    /// assert_eq!(probe.requests_missing_start_time(), 0);
+    /// ```
+    pub fn requests_missing_start_time(&self) -> usize {
+        self.config.missing_start_time.load(Ordering::Relaxed)
+    }
+}
+
+// When the request arrived, by the wall clock and by the monotonic clock.
+#[derive(Clone, Copy)]
+struct Start {
+    time: time::Tm,
+    monotonic_ns: u64,
 }
 
 struct StartTime;
-impl Key for StartTime { type Value = time::Tm; }
+impl Key for StartTime { type Value = Start; }
 
 impl Logger {
     fn initialise(&self, req: &mut Request) {
-        req.extensions.insert::<StartTime>(time::now());
+        let start = self.start();
+        req.extensions.insert::<StartTime>(start);
+
+        if let Some(ref header) = self.config.request_id_header {
+            let id = request_id::request_id(req, header);
+            req.extensions.insert::<RequestId>(id);
+        }
+    }
+
+    // Read the clocks as a request arrives.
+    fn start(&self) -> Start {
+        let clock = &self.config.clock;
+        Start { time: clock.now(), monotonic_ns: clock.monotonic_ns() }
+    }
+
+    // The nanoseconds since `start`, by the monotonic clock.
+    fn elapsed_ns(&self, start: &Start) -> u64 {
+        self.config.clock.monotonic_ns().saturating_sub(start.monotonic_ns)
+    }
+
+    // Echo the request id on the response, and log the request.
+    fn finish(&self, req: &mut Request, res: &mut Response) -> IronResult<()> {
+        if let Some(ref header) = self.config.request_id_header {
+            if let Some(id) = req.extensions.get::<RequestId>() {
+                res.headers.set_raw(header.clone(), vec![id.clone().into_bytes()]);
+            }
+        }
+
+        self.log(req, res)
+    }
+
+    // Note that the before half of the logger has not run for this request,
+    // warning about the misconfigured chain the first time it happens.
+    fn missing_start_time(&self, req: &Request) {
+        if self.count_missing_start_time() {
+            warn!(target: &self.config.target, "No start time was recorded for {} {}: link the logger BeforeMiddleware first in the chain, \
+                   or link a single logger with `Chain::link_around`. Response times will be logged as `{}`.",
+                  req.method, redact::redact_query(&format!("{}", req.url), &self.config.redacted_query_params),
+                  self.config.missing_response_time);
+        }
+    }
+
+    // Count a request without a start time, returning whether it is the first.
+    fn count_missing_start_time(&self) -> bool {
+        self.config.missing_start_time.fetch_add(1, Ordering::Relaxed) == 0
     }
 
-    fn log(&self, req: &mut Request, res: &Response) -> IronResult<()> {
-        let entry_time = *req.extensions.get::<StartTime>().unwrap();
+    // Whether the include and exclude filters allow logging this request.
+    fn is_selected(&self, req: &Request, path: &str) -> bool {
+        let config = &self.config;
+        let matches = |filter: &RequestFilter| filter.matches(&req.method, path);
+
+        (config.include.is_empty() || config.include.iter().any(&matches)) &&
+            !config.exclude.iter().any(&matches)
+    }
+
+    // Whether the request took longer than the slow threshold for its path.
+    // The threshold with the longest matching prefix applies.
+    fn is_slow(&self, path: &str, duration: Option<Duration>) -> bool {
+        let threshold = self.config.slow_thresholds.iter()
+            .filter(|&&(ref prefix, _)| path.starts_with(&prefix[..]))
+            .max_by_key(|&&(ref prefix, _)| prefix.len())
+            .map(|&(_, threshold)| threshold)
+            .or(self.config.slow_threshold);
+
+        match (threshold, duration) {
+            (Some(threshold), Some(duration)) => duration > threshold,
+            _ => false
+        }
+    }
+
+    fn log(&self, req: &mut Request, res: &mut Response) -> IronResult<()> {
+        let (entry_time, response_time_ns) = match req.extensions.get::<StartTime>() {
+            Some(start) => (start.time, Some(self.elapsed_ns(start))),
+            None => {
+                self.missing_start_time(req);
+                (self.config.clock.now(), None)
+            }
+        };
+        let duration = response_time_ns.map(|ns| Duration::new(ns / 1000000000, (ns % 1000000000) as u32));
+
+        // Slow requests are always logged, whatever the filters say.
+        let path = request_path(req);
+        let slow = self.is_slow(&path, duration);
+        if !slow {
+            if !self.is_selected(req, &path) {
+                return Ok(());
+            }
+
+            if let Some(ref predicate) = self.config.predicate {
+                if !predicate(req, res, duration.unwrap_or(Duration::new(0, 0))) {
+                    return Ok(());
+                }
+            }
+        }
+
+        let code = res.status.map(|status| status.to_u16());
+        let sample_rate = if slow { 1.0 } else { self.config.sampler.rate(code) };
+        if !self.config.sampler.keep(req, sample_rate) {
+            return Ok(());
+        }
 
-        let response_time = time::now() - entry_time;
-        let response_time_ms = (response_time.num_seconds() * 1000) as f64 + (response_time.num_nanoseconds().unwrap_or(0) as f64) / 1000000.0;
-        let Format(format) = self.format.clone().unwrap_or_default();
+        let Format(ref format) = self.config.format;
 
-        {
+        let mut pieces = {
             let render = |text: &FormatText| {
                 match *text {
-                    Str(ref string) => string.clone(),
-                    Method => format!("{}", req.method),
-                    URI => format!("{}", req.url),
-                    Status => res.status
-                        .map(|status| format!("{}", status))
-                        .unwrap_or("<missing status code>".to_owned()),
-                    ResponseTime => format!("{} ms", response_time_ms),
-                    RemoteAddr => format!("{}", req.remote_addr),
-                    RequestTime => format!("{}", entry_time.strftime("%Y-%m-%dT%H:%M:%S.%fZ%z").unwrap()),
+                    Str(ref string) => LogPiece::Literal(string.clone()),
+                    Method => LogPiece::string("method", format!("{}", req.method)),
+                    URI => LogPiece::string("uri",
+                        redact::redact_query(&format!("{}", req.url), &self.config.redacted_query_params)),
+                    Status => match res.status {
+                        Some(status) => LogPiece::field("status", format!("{}", status),
+                                                        Value::Int(status.to_u16() as u64)),
+                        None => LogPiece::field("status", "<missing status code>".to_owned(), Value::Null),
+                    },
+                    ResponseTime(ref duration_format) => match response_time_ns {
+                        Some(ns) => LogPiece::field("response_time_ms", duration_format.render(ns),
+                                                    Value::Float(ns as f64 / 1000000.0)),
+                        None => LogPiece::field("response_time_ms", self.config.missing_response_time.clone(),
+                                                Value::Null),
+                    },
+                    RemoteAddr { anonymise } => LogPiece::string("remote_addr",
+                        render_ip(&self.config, req.remote_addr.ip(), Some(req.remote_addr.port()), anonymise)),
+                    RequestTime(ref time_format) => LogPiece::field("request_time", time_format.render(&entry_time),
+                                                                    Value::Str(TimeFormat::default().render(&entry_time))),
+                    RemoteIp { anonymise } => LogPiece::string("remote_ip",
+                        render_ip(&self.config, req.remote_addr.ip(), None, anonymise)),
+                    ClientIp { anonymise } => {
+                        let ip = client_ip::client_ip(req, self.config.forwarded_header, &self.config.trusted_proxies);
+                        LogPiece::string("client_ip", render_ip(&self.config, ip, None, anonymise))
+                    },
+                    RemoteUser => LogPiece::escaped("remote_user", remote_user(req), "-"),
+                    RequestLine => {
+                        let target = redact::redact_query(&request_target(req), &self.config.redacted_query_params);
+                        LogPiece::escaped("request_line", Some(format!("{} {} {}", req.method, target, req.version)), "-")
+                    },
+                    StatusCode => match res.status {
+                        Some(status) => LogPiece::field("status", format!("{}", status.to_u16()),
+                                                        Value::Int(status.to_u16() as u64)),
+                        None => LogPiece::field("status", "-".to_owned(), Value::Null),
+                    },
+                    BytesSent => LogPiece::BytesSent,
+                    FormatText::RequestId =>
+                        LogPiece::escaped("request_id", req.extensions.get::<RequestId>().cloned(), "-"),
+                    RequestHeader { ref name, ref fallback } =>
+                        LogPiece::escaped(header_key("req_header_", name), logged_header_value(&self.config, &req.headers, name), fallback),
+                    ResponseHeader { ref name, ref fallback } =>
+                        LogPiece::escaped(header_key("res_header_", name), logged_header_value(&self.config, &res.headers, name), fallback),
                 }
             };
 
-            let lg = format.iter().map(|unit| render(&unit.text)).collect::<Vec<String>>().join("");
-            info!("{}", lg);
+            format.iter().map(|unit| render(&unit.text)).collect::<Vec<LogPiece>>()
+        };
+
+        if sample_rate < 1.0 {
+            pieces.push(LogPiece::field("sample_rate", format!(" sample_rate={}", sample_rate),
+                                        Value::Float(sample_rate)));
+        }
+
+        let mut level = self.config.levels.level(code);
+        if slow {
+            pieces.push(LogPiece::field("slow", " slow=true".to_owned(), Value::Bool(true)));
+            level = cmp::min(level, LogLevel::Warn);
+        }
+
+        match res.body.take() {
+            Some(body) if self.config.log_after_body => {
+                let config = self.config.clone();
+                let emit = move |written| emit(&config, level, &pieces, Some(written));
+                res.body = Some(Box::new(CountingBody::new(body, Box::new(emit))));
+            },
+            body => {
+                res.body = body;
+                emit(&self.config, level, &pieces, res.headers.get::<ContentLength>().map(|length| length.0));
+            }
         }
 
         Ok(())
     }
 }
 
+// Assemble the log line and write it out, with the number of bytes sent if known.
+fn emit(config: &Config, level: LogLevel, pieces: &[LogPiece], bytes_sent: Option<u64>) {
+    log!(target: &config.target, level, "{}", output::render_line(pieces, bytes_sent, config.output_mode));
+}
+
+// Render an IP address and optional port, anonymised if either the
+// placeholder or the `Logger` configuration asks for it.
+fn render_ip(config: &Config, ip: IpAddr, port: Option<u16>, anonymise: bool) -> String {
+    match (config.ip_anonymisation, port) {
+        (Some(mode), _) => mode.render(ip, port),
+        (None, _) if anonymise => IpAnonymisation::default().render(ip, port),
+        (None, Some(port)) => format!("{}", SocketAddr::new(ip, port)),
+        (None, None) => format!("{}", ip),
+    }
+}
+
+// The HTTP Basic username of the request, if there is a non-empty one.
+fn remote_user(req: &Request) -> Option<String> {
+    req.headers.get::<Authorization<Basic>>()
+        .map(|auth| auth.0.username.clone())
+        .and_then(|user| if user.is_empty() { None } else { Some(user) })
+}
+
+// The structured output key for a header placeholder, e.g. `req_header_x_trace_id`.
+fn header_key(prefix: &str, name: &str) -> String {
+    let mut key = prefix.to_owned();
+    key.extend(name.chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' }));
+    key
+}
+
+// The path of the request, e.g. `/a/b`.
+fn request_path(req: &Request) -> String {
+    format!("/{}", req.url.path().join("/"))
+}
+
+// The path and query of the request, as they appeared in the request line.
+fn request_target(req: &Request) -> String {
+    let mut target = request_path(req);
+    if let Some(query) = req.url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    target
+}
+
+// Look up a header to be logged, masking each of its values if it is one of
+// the sensitive headers configured on the `Logger`, and anonymising any client
+// addresses in it if the `Logger` anonymises addresses.
+fn logged_header_value(config: &Config, headers: &Headers, name: &str) -> Option<String> {
+    if redact::is_masked_header(name, &config.masked_headers) {
+        return headers.get_raw(name).map(|values| redact::mask_values(&config.header_mask, values));
+    }
+
+    // Headers carrying client addresses are anonymised along with the
+    // addresses the logger writes itself.
+    let value = header_value(headers, name);
+    match config.ip_anonymisation {
+        Some(mode) => value.map(|value| anonymise::forwarding_header(mode, name, &value).unwrap_or(value)),
+        None => value,
+    }
+}
+
+// Look up a header by name, joining the values of a repeated header with `, `.
+fn header_value(headers: &Headers, name: &str) -> Option<String> {
+    headers.get_raw(name).map(|values| {
+        values.iter()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .collect::<Vec<String>>()
+            .join(", ")
+    })
+}
+
 impl BeforeMiddleware for Logger {
     fn before(&self, req: &mut Request) -> IronResult<()> {
         self.initialise(req);
@@ -92,13 +380,117 @@ impl BeforeMiddleware for Logger {
 }
 
 impl AfterMiddleware for Logger {
-    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
-        try!(self.log(req, &res));
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        try!(self.finish(req, &mut res));
         Ok(res)
     }
 
-    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
-        try!(self.log(req, &err.response));
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        try!(self.finish(req, &mut err.response));
         Err(err)
     }
 }
+
+impl AroundMiddleware for Logger {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(LoggerHandler { logger: self, handler: handler })
+    }
+}
+
+// A handler wrapped by a `Logger` linked as `AroundMiddleware`.
+struct LoggerHandler {
+    logger: Logger,
+    handler: Box<Handler>,
+}
+
+impl Handler for LoggerHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.logger.initialise(req);
+
+        match self.handler.handle(req) {
+            Ok(mut res) => {
+                try!(self.logger.finish(req, &mut res));
+                Ok(res)
+            },
+            Err(mut err) => {
+                try!(self.logger.finish(req, &mut err.response));
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use time;
+
+    use format::DurationFormat;
+
+    use super::{Clock, Logger, LoggerBuilder};
+
+    // A clock that only moves when told to.
+    #[derive(Clone)]
+    struct FakeClock {
+        monotonic_ns: Arc<Mutex<u64>>,
+    }
+
+    impl FakeClock {
+        fn advance(&self, ns: u64) {
+            *self.monotonic_ns.lock().unwrap() += ns;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn monotonic_ns(&self) -> u64 {
+            *self.monotonic_ns.lock().unwrap()
+        }
+
+        fn now(&self) -> time::Tm {
+            time::at_utc(time::Timespec::new(971211336, 0))
+        }
+    }
+
+    #[test]
+    fn measures_response_times_with_the_clock() {
+        let clock = FakeClock { monotonic_ns: Arc::new(Mutex::new(1000)) };
+        let logger = LoggerBuilder::new().clock(clock.clone()).build_around();
+
+        let start = logger.start();
+        assert_eq!(start.time.to_timespec().sec, 971211336);
+        assert_eq!(start.monotonic_ns, 1000);
+
+        clock.advance(1500000);
+        assert_eq!(logger.elapsed_ns(&start), 1500000);
+        assert_eq!(DurationFormat::default().render(logger.elapsed_ns(&start)), "1.5 ms");
+    }
+
+    #[test]
+    fn never_measures_negative_response_times() {
+        let clock = FakeClock { monotonic_ns: Arc::new(Mutex::new(1000)) };
+        let logger = LoggerBuilder::new().clock(clock.clone()).build_around();
+
+        let mut start = logger.start();
+        start.monotonic_ns += 1;
+        assert_eq!(logger.elapsed_ns(&start), 0);
+    }
+
+    #[test]
+    fn counts_requests_missing_a_start_time() {
+        let (before, after) = Logger::new(None);
+        let probe = after.clone();
+        assert_eq!(probe.requests_missing_start_time(), 0);
+
+        // Only the first is warned about, but all are counted, by every
+        // clone of either half of the logger.
+        assert!(after.count_missing_start_time());
+        assert!(!after.count_missing_start_time());
+        assert!(!before.count_missing_start_time());
+        assert_eq!(probe.requests_missing_start_time(), 3);
+        assert_eq!(before.requests_missing_start_time(), 3);
+
+        let (_, other) = Logger::new(None);
+        assert_eq!(other.requests_missing_start_time(), 0);
+    }
+}
@@ -0,0 +1,336 @@
+//! Rendering of log lines as plain text or structured records.
+
+use std::fmt::Write;
+
+/// How the `Logger` writes out each log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Concatenate the rendered `FormatUnit`s into a line of text. This is the default.
+    Text,
+    /// Write a JSON object per line, with a key for every placeholder in the `Format`.
+    /// Literal text in the format string is left out.
+    ///
+    /// ```ignore
+    /// {"method":"GET","uri":"http://localhost:3000/","status":200,"response_time_ms":0.25}
+    /// ```
+    Json,
+    /// Write a line of space-separated `key=value` pairs in the logfmt style, with a key for
+    /// every placeholder in the `Format`. Literal text in the format string is left out.
+    ///
+    /// ```ignore
+    /// method=GET uri="http://localhost:3000/a b" status=200 duration_ms=0.25
+    /// ```
+    Logfmt,
+}
+
+impl Default for OutputMode {
+    fn default() -> OutputMode {
+        OutputMode::Text
+    }
+}
+
+/// A typed value of a field, for structured output modes.
+pub enum Value {
+    Str(String),
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+/// A field rendered from a placeholder, both as text and as a typed value.
+pub struct Field {
+    pub key: String,
+    pub text: String,
+    pub value: Value,
+}
+
+/// A rendered part of a log line. The number of bytes sent may not be known
+/// until after the response body has been written.
+pub enum LogPiece {
+    Literal(String),
+    Field(Field),
+    BytesSent,
+}
+
+impl LogPiece {
+    /// A field whose value is the same string as its text.
+    pub fn string<K: Into<String>>(key: K, text: String) -> LogPiece {
+        LogPiece::field(key, text.clone(), Value::Str(text))
+    }
+
+    /// A string field that may be absent, in which case its text is `fallback`
+    /// and its value is null. Its text is escaped with `escape_log_item`,
+    /// while its value is left as it is.
+    pub fn escaped<K: Into<String>>(key: K, text: Option<String>, fallback: &str) -> LogPiece {
+        match text {
+            Some(text) => LogPiece::field(key, escape_log_item(&text), Value::Str(text)),
+            None => LogPiece::field(key, fallback.to_owned(), Value::Null),
+        }
+    }
+
+    /// A field with distinct text and value.
+    pub fn field<K: Into<String>>(key: K, text: String, value: Value) -> LogPiece {
+        LogPiece::Field(Field { key: key.into(), text: text, value: value })
+    }
+}
+
+/// Escape text taken from the request or response for a plain text log line,
+/// as Apache does for the fields of the Common Log Format: `"` and `\` are
+/// escaped with a backslash, and control characters are written as C escapes
+/// such as `\n` or `\x1b`. This keeps a quoted field quoted, and stops a
+/// client from forging extra log lines.
+pub fn escape_log_item(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\x08' => escaped.push_str("\\b"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\x0b' => escaped.push_str("\\v"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => write!(escaped, "\\x{:02x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Assemble the pieces of a log line in the given output mode, with the number
+/// of bytes sent if it is known.
+pub fn render_line(pieces: &[LogPiece], bytes_sent: Option<u64>, mode: OutputMode) -> String {
+    match mode {
+        OutputMode::Text => render_text(pieces, bytes_sent),
+        OutputMode::Json => render_json(pieces, bytes_sent),
+        OutputMode::Logfmt => render_logfmt(pieces, bytes_sent),
+    }
+}
+
+// Collect the distinct keys of the structured fields and their values.
+// The same placeholder may appear more than once in a format string, but
+// should only be written out once.
+fn structured_fields<'a>(pieces: &'a [LogPiece], bytes_sent: &'a Value) -> Vec<(&'a str, &'a Value)> {
+    let mut fields: Vec<(&str, &Value)> = Vec::new();
+
+    for piece in pieces {
+        let (key, value) = match *piece {
+            LogPiece::Literal(_) => continue,
+            LogPiece::Field(ref field) => (&field.key[..], &field.value),
+            LogPiece::BytesSent => ("bytes_sent", bytes_sent),
+        };
+
+        if !fields.iter().any(|&(existing, _)| existing == key) {
+            fields.push((key, value));
+        }
+    }
+
+    fields
+}
+
+fn render_text(pieces: &[LogPiece], bytes_sent: Option<u64>) -> String {
+    let mut line = String::new();
+
+    for piece in pieces {
+        match *piece {
+            LogPiece::Literal(ref text) => line.push_str(text),
+            LogPiece::Field(ref field) => line.push_str(&field.text),
+            LogPiece::BytesSent => match bytes_sent {
+                Some(bytes) if bytes > 0 => write!(line, "{}", bytes).unwrap(),
+                _ => line.push('-'),
+            },
+        }
+    }
+
+    line
+}
+
+fn render_json(pieces: &[LogPiece], bytes_sent: Option<u64>) -> String {
+    let mut line = String::from("{");
+    let bytes_sent = bytes_sent.map(Value::Int).unwrap_or(Value::Null);
+
+    for (i, (key, value)) in structured_fields(pieces, &bytes_sent).into_iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+
+        write_json_string(&mut line, key);
+        line.push(':');
+        match *value {
+            Value::Str(ref string) => write_json_string(&mut line, string),
+            Value::Int(int) => write!(line, "{}", int).unwrap(),
+            Value::Float(float) if float.is_finite() => write!(line, "{}", float).unwrap(),
+            Value::Bool(boolean) => write!(line, "{}", boolean).unwrap(),
+            Value::Float(_) | Value::Null => line.push_str("null"),
+        }
+    }
+
+    line.push('}');
+    line
+}
+
+fn write_json_string(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn render_logfmt(pieces: &[LogPiece], bytes_sent: Option<u64>) -> String {
+    let mut line = String::new();
+    let bytes_sent = bytes_sent.map(Value::Int).unwrap_or(Value::Null);
+
+    for (i, (key, value)) in structured_fields(pieces, &bytes_sent).into_iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+
+        // The response time is conventionally called a duration in logfmt.
+        line.push_str(if key == "response_time_ms" { "duration_ms" } else { key });
+        line.push('=');
+        match *value {
+            Value::Str(ref string) => write_logfmt_string(&mut line, string),
+            Value::Int(int) => write!(line, "{}", int).unwrap(),
+            Value::Float(float) if float.is_finite() => write!(line, "{}", float).unwrap(),
+            Value::Bool(boolean) => write!(line, "{}", boolean).unwrap(),
+            Value::Float(_) | Value::Null => line.push_str("null"),
+        }
+    }
+
+    line
+}
+
+fn write_logfmt_string(out: &mut String, string: &str) {
+    let needs_quotes = string.is_empty() || string.chars().any(|c| {
+        c == ' ' || c == '"' || c == '=' || c == '\\' || c.is_control()
+    });
+    if !needs_quotes {
+        out.push_str(string);
+        return;
+    }
+
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{{{:04x}}}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use super::{escape_log_item, render_line, LogPiece, OutputMode, Value};
+
+    #[test]
+    fn escapes_log_items_like_apache() {
+        assert_eq!(escape_log_item("Mozilla/5.0 (X11)"), "Mozilla/5.0 (X11)");
+        assert_eq!(escape_log_item(r#"say "hi" \o/"#), r#"say \"hi\" \\o/"#);
+        assert_eq!(escape_log_item("a\nb\tc\r\x08\x0b"), r"a\nb\tc\r\b\v");
+        assert_eq!(escape_log_item("\x1b[31m\x00\x7f"), r"\x1b[31m\x00\x7f");
+        assert_eq!(escape_log_item("Bearer abc…"), "Bearer abc…");
+    }
+
+    #[test]
+    fn renders_json_with_typed_values() {
+        let pieces = vec![
+            LogPiece::Literal("ignored ".to_owned()),
+            LogPiece::string("method", "GET".to_owned()),
+            LogPiece::field("status", "200 OK".to_owned(), Value::Int(200)),
+            LogPiece::field("response_time_ms", "1.5 ms".to_owned(), Value::Float(1.5)),
+            LogPiece::field("slow", " slow=true".to_owned(), Value::Bool(true)),
+            LogPiece::BytesSent,
+        ];
+        assert_eq!(render_line(&pieces, Some(5), OutputMode::Json),
+                   r#"{"method":"GET","status":200,"response_time_ms":1.5,"slow":true,"bytes_sent":5}"#);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        let pieces = vec![LogPiece::string("uri", "/a\"b\\c\n\r\t\u{1}\u{1f}é".to_owned())];
+        assert_eq!(render_line(&pieces, None, OutputMode::Json),
+                   r#"{"uri":"/a\"b\\c\n\r\t\u0001\u001fé"}"#);
+    }
+
+    #[test]
+    fn renders_missing_values_as_json_null() {
+        let pieces = vec![
+            LogPiece::field("status", "<missing status code>".to_owned(), Value::Null),
+            LogPiece::field("response_time_ms", "NaN".to_owned(), Value::Float(f64::NAN)),
+            LogPiece::escaped("remote_user", None, "-"),
+            LogPiece::BytesSent,
+        ];
+        assert_eq!(render_line(&pieces, None, OutputMode::Json),
+                   r#"{"status":null,"response_time_ms":null,"remote_user":null,"bytes_sent":null}"#);
+    }
+
+    #[test]
+    fn writes_one_json_key_per_placeholder() {
+        let pieces = vec![
+            LogPiece::string("method", "GET".to_owned()),
+            LogPiece::Literal(" ".to_owned()),
+            LogPiece::string("method", "GET".to_owned()),
+            LogPiece::BytesSent,
+            LogPiece::BytesSent,
+        ];
+        assert_eq!(render_line(&pieces, Some(0), OutputMode::Json), r#"{"method":"GET","bytes_sent":0}"#);
+        assert_eq!(render_line(&pieces, Some(0), OutputMode::Text), "GET GET--");
+    }
+
+    #[test]
+    fn renders_logfmt() {
+        let pieces = vec![
+            LogPiece::string("method", "GET".to_owned()),
+            LogPiece::Literal(" ".to_owned()),
+            LogPiece::string("uri", "/a b".to_owned()),
+            LogPiece::field("status", "200 OK".to_owned(), Value::Int(200)),
+            LogPiece::field("response_time_ms", "3.2 ms".to_owned(), Value::Float(3.2)),
+        ];
+        assert_eq!(render_line(&pieces, None, OutputMode::Logfmt), "method=GET uri=\"/a b\" status=200 duration_ms=3.2");
+    }
+
+    #[test]
+    fn quotes_and_escapes_logfmt_values() {
+        let pieces = vec![
+            LogPiece::string("query", "a=b".to_owned()),
+            LogPiece::string("agent", "say \"hi\" \\o/".to_owned()),
+            LogPiece::string("line", "a\nb\u{1b}".to_owned()),
+            LogPiece::string("empty", "".to_owned()),
+            LogPiece::string("plain", "é/ok".to_owned()),
+        ];
+        assert_eq!(render_line(&pieces, None, OutputMode::Logfmt),
+                   r#"query="a=b" agent="say \"hi\" \\o/" line="a\nb\u{001b}" empty="" plain=é/ok"#);
+    }
+
+    #[test]
+    fn renders_missing_logfmt_values_as_null() {
+        let pieces = vec![
+            LogPiece::field("status", "<missing status code>".to_owned(), Value::Null),
+            LogPiece::field("response_time_ms", "-".to_owned(), Value::Null),
+            LogPiece::field("slow", " slow=true".to_owned(), Value::Bool(true)),
+            LogPiece::BytesSent,
+        ];
+        assert_eq!(render_line(&pieces, None, OutputMode::Logfmt),
+                   "status=null duration_ms=null slow=true bytes_sent=null");
+    }
+}
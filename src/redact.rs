@@ -0,0 +1,265 @@
+//! Redaction of sensitive values before they are logged.
+
+use std::cmp;
+use std::fmt;
+
+use filter::glob_match;
+use hash::siphash;
+
+/// The text that replaces a redacted value.
+pub const REDACTED: &'static str = "[REDACTED]";
+
+/// The headers whose values are masked unless configured otherwise, as
+/// lowercase glob patterns: the standard credential headers, and the usual
+/// names of custom ones such as `X-Api-Key`, `X-Auth-Token` and
+/// `X-Client-Secret`.
+pub const DEFAULT_MASKED_HEADERS: &'static [&'static str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "*api-key",
+    "*-token",
+    "*-secret",
+];
+
+/// How the `Logger` masks the values of sensitive headers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeaderMask {
+    /// Replace the whole value with `[REDACTED]`. This is the default.
+    Redact,
+    /// Keep up to the given number of characters, but never more than half of the value, and
+    /// replace the rest with `…`. With `Prefix(10)`, `Bearer abc123def456ghi` is logged as
+    /// `Bearer abc…`.
+    Prefix(usize),
+    /// Replace the value with a hash keyed by the given secret, so that requests with the same
+    /// value can be correlated without the value being revealed. The key should be random and
+    /// kept secret.
+    Hash([u8; 16]),
+}
+
+impl Default for HeaderMask {
+    fn default() -> HeaderMask {
+        HeaderMask::Redact
+    }
+}
+
+// Never show the `Hash` key, even in a debug dump of the `Logger` configuration.
+impl fmt::Debug for HeaderMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderMask::Redact => f.write_str("Redact"),
+            HeaderMask::Prefix(length) => write!(f, "Prefix({})", length),
+            HeaderMask::Hash(_) => f.write_str("Hash(..)"),
+        }
+    }
+}
+
+impl HeaderMask {
+    /// Mask a single header value.
+    pub fn mask(&self, value: &str) -> String {
+        match *self {
+            HeaderMask::Redact => REDACTED.to_owned(),
+            HeaderMask::Prefix(length) => {
+                // Keeping a short value whole would reveal it.
+                let length = cmp::min(length, value.chars().count() / 2);
+                let mut masked: String = value.chars().take(length).collect();
+                masked.push('…');
+                masked
+            },
+            HeaderMask::Hash(ref key) => format!("{:016x}", siphash(key, value.as_bytes())),
+        }
+    }
+}
+
+/// Mask each of the values of a repeated header separately, joining them with
+/// `, ` as unmasked headers are.
+pub fn mask_values(mask: &HeaderMask, values: &[Vec<u8>]) -> String {
+    values.iter()
+        .map(|value| mask.mask(&String::from_utf8_lossy(value)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Whether the header `name` matches any of the lowercase glob `patterns`.
+pub fn is_masked_header(name: &str, patterns: &[String]) -> bool {
+    let name = name.to_lowercase();
+    patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes(), None))
+}
+
+/// Replace the values of the query parameters in `url` whose names match any
+/// of the lowercase glob `patterns` with `[REDACTED]`. The query is the part
+/// of `url` after the first `?` and before any fragment, so `url` can be a
+/// full URL or just a path and query.
+pub fn redact_query(url: &str, patterns: &[String]) -> String {
+    let start = match url.find('?') {
+        Some(question) if !patterns.is_empty() => question + 1,
+        _ => return url.to_owned(),
+    };
+    let end = url[start..].find('#').map(|hash| start + hash).unwrap_or(url.len());
+
+    let mut redacted = url[..start].to_owned();
+    for (i, pair) in url[start..end].split('&').enumerate() {
+        if i > 0 {
+            redacted.push('&');
+        }
+
+        match pair.find('=') {
+            Some(eq) if is_sensitive(&pair[..eq], patterns) => {
+                redacted.push_str(&pair[..eq + 1]);
+                redacted.push_str(REDACTED);
+            },
+            _ => redacted.push_str(pair),
+        }
+    }
+    redacted.push_str(&url[end..]);
+
+    redacted
+}
+
+fn is_sensitive(name: &str, patterns: &[String]) -> bool {
+    let name = decode_lowercase(name);
+    patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), &name, None))
+}
+
+// Decode a form-encoded parameter name and lowercase it, so that an encoded
+// name such as `Pass%57ord` cannot slip past a pattern such as `password`.
+fn decode_lowercase(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            hex_digit(bytes[i + 1]).and_then(|high| hex_digit(bytes[i + 2]).map(|low| high << 4 | low))
+        } else {
+            None
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte.to_ascii_lowercase());
+                i += 3;
+            },
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i].to_ascii_lowercase() });
+                i += 1;
+            },
+        }
+    }
+
+    decoded
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::siphash;
+
+    use super::{is_masked_header, mask_values, redact_query, HeaderMask, DEFAULT_MASKED_HEADERS};
+
+    fn redact(url: &str, patterns: &[&str]) -> String {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        redact_query(url, &patterns)
+    }
+
+    #[test]
+    fn redacts_names_in_any_case() {
+        assert_eq!(redact("/login?user=bob&Password=hunter2", &["password"]),
+                   "/login?user=bob&Password=[REDACTED]");
+        assert_eq!(redact("/login?PASSWORD=hunter2&password=x", &["password"]),
+                   "/login?PASSWORD=[REDACTED]&password=[REDACTED]");
+    }
+
+    #[test]
+    fn redacts_names_matching_globs() {
+        let patterns = &["*token*"];
+        assert_eq!(redact("/a?access_token=1&token=2&tokens_left=3&tok=4", patterns),
+                   "/a?access_token=[REDACTED]&token=[REDACTED]&tokens_left=[REDACTED]&tok=4");
+        assert_eq!(redact("/a?api_key=1&api_keys=2", &["api_?ey"]), "/a?api_key=[REDACTED]&api_keys=2");
+    }
+
+    #[test]
+    fn decodes_names_before_matching() {
+        assert_eq!(redact("/a?Pass%57ord=1", &["password"]), "/a?Pass%57ord=[REDACTED]");
+        assert_eq!(redact("/a?api+key=1&api%20key=2", &["api key"]), "/a?api+key=[REDACTED]&api%20key=[REDACTED]");
+        assert_eq!(redact("/a?pass%=1&pass%7=2&pass%zz=3", &["pass%*"]),
+                   "/a?pass%=[REDACTED]&pass%7=[REDACTED]&pass%zz=[REDACTED]");
+        assert_eq!(redact("/a?password%=1", &["password"]), "/a?password%=1");
+    }
+
+    #[test]
+    fn leaves_parameters_without_values() {
+        assert_eq!(redact("/a?password&x=1", &["password"]), "/a?password&x=1");
+        assert_eq!(redact("/a?&&password=", &["password"]), "/a?&&password=[REDACTED]");
+    }
+
+    #[test]
+    fn keeps_the_fragment() {
+        assert_eq!(redact("/a?token=1#token=2", &["token"]), "/a?token=[REDACTED]#token=2");
+    }
+
+    #[test]
+    fn redacts_full_urls_and_paths() {
+        assert_eq!(redact("https://example.com:8443/a/b?token=1&x=2", &["token"]),
+                   "https://example.com:8443/a/b?token=[REDACTED]&x=2");
+        assert_eq!(redact("/a/b?token=1&x=2", &["token"]), "/a/b?token=[REDACTED]&x=2");
+        assert_eq!(redact("/a/b", &["token"]), "/a/b");
+        assert_eq!(redact("/a/b?", &["token"]), "/a/b?");
+    }
+
+    #[test]
+    fn leaves_urls_alone_without_patterns() {
+        assert_eq!(redact("/login?password=hunter2#x", &[]), "/login?password=hunter2#x");
+    }
+
+    #[test]
+    fn keeps_at_most_half_of_the_value_as_a_prefix() {
+        assert_eq!(HeaderMask::Prefix(10).mask("Bearer abc123def456ghi"), "Bearer abc…");
+        assert_eq!(HeaderMask::Prefix(10).mask("Bearer abc123"), "Bearer…");
+        assert_eq!(HeaderMask::Prefix(10).mask("ab"), "a…");
+        assert_eq!(HeaderMask::Prefix(10).mask("a"), "…");
+        assert_eq!(HeaderMask::Prefix(0).mask("Bearer abc123def456ghi"), "…");
+        assert_eq!(HeaderMask::Prefix(4).mask("ééééé"), "éé…");
+    }
+
+    #[test]
+    fn redacts_whole_values() {
+        assert_eq!(HeaderMask::Redact.mask("Basic dXNlcjpwYXNz"), "[REDACTED]");
+        assert_eq!(HeaderMask::default(), HeaderMask::Redact);
+    }
+
+    #[test]
+    fn hashes_values_with_the_key() {
+        let key = [7; 16];
+        let hashed = HeaderMask::Hash(key).mask("session=abc");
+        assert_eq!(hashed, format!("{:016x}", siphash(&key, b"session=abc")));
+        assert_eq!(hashed.len(), 16);
+        assert!(hashed.chars().all(|c| c.is_digit(16) && !c.is_uppercase()));
+        assert_eq!(HeaderMask::Hash(key).mask("session=abc"), hashed);
+        assert!(HeaderMask::Hash([8; 16]).mask("session=abc") != hashed);
+        assert_eq!(format!("{:?}", HeaderMask::Hash(key)), "Hash(..)");
+    }
+
+    #[test]
+    fn masks_repeated_values_separately() {
+        let values = vec![b"a=secret1; Path=/".to_vec(), b"b=secret2; HttpOnly".to_vec()];
+        assert_eq!(mask_values(&HeaderMask::Redact, &values), "[REDACTED], [REDACTED]");
+        assert_eq!(mask_values(&HeaderMask::Prefix(4), &values), "a=se…, b=se…");
+    }
+
+    #[test]
+    fn matches_header_names_in_any_case() {
+        let defaults: Vec<String> = DEFAULT_MASKED_HEADERS.iter().map(|&name| name.to_owned()).collect();
+        for name in &["Authorization", "PROXY-AUTHORIZATION", "cookie", "Set-Cookie", "X-Api-Key", "api-key",
+                      "X-Auth-Token", "X-CSRF-Token", "X-Client-Secret"] {
+            assert!(is_masked_header(name, &defaults), "{}", name);
+        }
+        for name in &["Accept", "User-Agent", "X-Request-Id", "Token", "X-Forwarded-For"] {
+            assert!(!is_masked_header(name, &defaults), "{}", name);
+        }
+    }
+}
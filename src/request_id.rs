@@ -0,0 +1,81 @@
+//! Request ids, for correlating log lines with each other.
+
+use iron::Request;
+use iron::typemap::Key;
+
+use hash::random;
+use header_value;
+
+/// The `typemap` key under which the `Logger` stores the id of each request, when configured with
+/// `LoggerBuilder::request_id`. Handlers and other middleware can read it to include in their
+/// own logs:
+///
+/// ```ignore
+/// let id = req.extensions.get::<RequestId>().map(|id| &id[..]).unwrap_or("-");
+/// ```
+pub struct RequestId;
+
+impl Key for RequestId { type Value = String; }
+
+// Incoming ids longer than this are replaced with a new one.
+const MAX_INCOMING_LENGTH: usize = 200;
+
+/// The id of a request: the value of the `header` request header if it is a
+/// plausible id, or else a newly generated one.
+pub fn request_id(req: &Request, header: &str) -> String {
+    match header_value(&req.headers, header) {
+        Some(id) if is_plausible(&id) => id,
+        _ => generate(),
+    }
+}
+
+// Only trust incoming ids made of letters, digits and `.`, `_`, `:` and `-`,
+// as used by UUIDs and trace ids, so they cannot be used to forge log lines,
+// break out of quoted fields or inject response headers.
+fn is_plausible(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_INCOMING_LENGTH &&
+        id.bytes().all(|b| (b < 0x80 && (b as char).is_alphanumeric()) || b"._:-".contains(&b))
+}
+
+// A new id of 32 hexadecimal digits.
+fn generate() -> String {
+    format!("{:016x}{:016x}", random(), random())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, is_plausible, MAX_INCOMING_LENGTH};
+
+    #[test]
+    fn accepts_common_id_formats() {
+        assert!(is_plausible("f81d4fae-7dec-11d0-a765-00a0c91e6bf6"));
+        assert!(is_plausible("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert!(is_plausible("1-5759e988-bd862e3fe1be46a994272793"));
+        assert!(is_plausible("req_01.abc:7"));
+        assert!(is_plausible(&"a".repeat(MAX_INCOMING_LENGTH)));
+    }
+
+    #[test]
+    fn rejects_implausible_ids() {
+        assert!(!is_plausible(""));
+        assert!(!is_plausible(&"a".repeat(MAX_INCOMING_LENGTH + 1)));
+        assert!(!is_plausible("abc def"));
+        assert!(!is_plausible("abc\ndef"));
+        assert!(!is_plausible("abc\rSet-Cookie: x=y"));
+        assert!(!is_plausible("abc\x00"));
+        assert!(!is_plausible("abc\x7f"));
+        assert!(!is_plausible("abc\"def"));
+        assert!(!is_plausible("abc\\def"));
+        assert!(!is_plausible("abc=def"));
+        assert!(!is_plausible("ïd"));
+    }
+
+    #[test]
+    fn generates_distinct_hex_ids() {
+        let id = generate();
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_digit(16)));
+        assert!(is_plausible(&id));
+        assert!(generate() != id);
+    }
+}
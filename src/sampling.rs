@@ -0,0 +1,159 @@
+//! Sampling of log lines, to log only a fraction of requests.
+
+use iron::Request;
+
+use hash::{fnv1a, random};
+use header_value;
+use level::StatusClass;
+
+/// The rates at which requests are sampled, and how the sampling decision is made.
+pub struct Sampler {
+    rate: f64,
+    class_rates: Vec<(StatusClass, f64)>,
+    header: Option<String>,
+}
+
+impl Sampler {
+    /// Keep every request. Once a lower rate is set, `5xx` responses are still
+    /// always kept unless given a rate of their own.
+    pub fn new() -> Sampler {
+        Sampler {
+            rate: 1.0,
+            class_rates: vec![(StatusClass::ServerError, 1.0)],
+            header: None,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = clamp(rate);
+    }
+
+    pub fn set_class_rate(&mut self, class: StatusClass, rate: f64) {
+        self.class_rates.retain(|&(existing, _)| existing != class);
+        self.class_rates.push((class, clamp(rate)));
+    }
+
+    pub fn set_header(&mut self, header: String) {
+        self.header = Some(header);
+    }
+
+    /// The rate at which to sample a response with the given status code.
+    pub fn rate(&self, code: Option<u16>) -> f64 {
+        let class = code.and_then(StatusClass::from_code);
+        self.class_rates.iter()
+            .find(|&&(existing, _)| Some(existing) == class)
+            .map(|&(_, rate)| rate)
+            .unwrap_or(self.rate)
+    }
+
+    /// Decide whether to keep a request sampled at `rate`.
+    ///
+    /// When sampling by header and the header is present, the decision is
+    /// made by hashing its value with 64-bit FNV-1a: the request is kept if
+    /// the hash divided by 2^64 is less than `rate`. Any service sampling on
+    /// the same header at the same rate makes the same decision.
+    pub fn keep(&self, req: &Request, rate: f64) -> bool {
+        let value = self.header.as_ref().and_then(|name| header_value(&req.headers, name));
+        keep_value(value.as_ref().map(|value| &value[..]), rate)
+    }
+}
+
+// Decide whether to keep a request sampled at `rate`, by the value of the
+// sampling header if there is one, or at random.
+fn keep_value(value: Option<&str>, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+
+    let sample = match value {
+        Some(value) => fnv1a(value.as_bytes()),
+        None => random(),
+    };
+
+    (sample as f64) < rate * 18446744073709551616.0
+}
+
+fn clamp(rate: f64) -> f64 {
+    if rate > 1.0 {
+        1.0
+    } else if rate > 0.0 {
+        rate
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use level::StatusClass;
+
+    use super::{clamp, keep_value, Sampler};
+
+    #[test]
+    fn clamps_rates() {
+        assert_eq!(clamp(-1.0), 0.0);
+        assert_eq!(clamp(0.0), 0.0);
+        assert_eq!(clamp(0.25), 0.25);
+        assert_eq!(clamp(1.0), 1.0);
+        assert_eq!(clamp(2.0), 1.0);
+        assert_eq!(clamp(f64::NAN), 0.0);
+        assert_eq!(clamp(f64::INFINITY), 1.0);
+    }
+
+    #[test]
+    fn keeps_server_errors_by_default() {
+        let mut sampler = Sampler::new();
+        assert_eq!(sampler.rate(Some(200)), 1.0);
+
+        sampler.set_rate(0.1);
+        assert_eq!(sampler.rate(Some(200)), 0.1);
+        assert_eq!(sampler.rate(Some(404)), 0.1);
+        assert_eq!(sampler.rate(Some(503)), 1.0);
+        assert_eq!(sampler.rate(None), 0.1);
+        assert_eq!(sampler.rate(Some(600)), 0.1);
+    }
+
+    #[test]
+    fn overrides_rates_per_status_class() {
+        let mut sampler = Sampler::new();
+        sampler.set_rate(0.1);
+        sampler.set_class_rate(StatusClass::ClientError, 0.5);
+        sampler.set_class_rate(StatusClass::ServerError, 0.25);
+        sampler.set_class_rate(StatusClass::ServerError, 0.75);
+
+        assert_eq!(sampler.rate(Some(200)), 0.1);
+        assert_eq!(sampler.rate(Some(404)), 0.5);
+        assert_eq!(sampler.rate(Some(500)), 0.75);
+
+        sampler.set_class_rate(StatusClass::Success, 7.0);
+        assert_eq!(sampler.rate(Some(204)), 1.0);
+    }
+
+    #[test]
+    fn samples_deterministically_by_header_value() {
+        for i in 0..100 {
+            let value = format!("trace-{}", i);
+            let decision = keep_value(Some(&value), 0.5);
+            for _ in 0..3 {
+                assert_eq!(keep_value(Some(&value), 0.5), decision);
+            }
+        }
+
+        let kept = (0..1000).filter(|i| keep_value(Some(&format!("trace-{}", i)), 0.5)).count();
+        assert!(kept > 400 && kept < 600, "{} of 1000 kept at 0.5", kept);
+    }
+
+    #[test]
+    fn keeps_all_or_nothing_at_the_edges() {
+        for i in 0..100 {
+            let value = format!("trace-{}", i);
+            assert!(keep_value(Some(&value), 1.0));
+            assert!(!keep_value(Some(&value), 0.0));
+            assert!(keep_value(None, 1.0));
+            assert!(!keep_value(None, 0.0));
+        }
+        assert!(!keep_value(Some(""), 0.0));
+    }
+}